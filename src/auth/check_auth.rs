@@ -8,12 +8,12 @@ use crate::models::{
 };
 
 /// Check if the request is authorized
-
 pub async fn is_authorized(
     req: &HttpRequest,
     state: web::Data<AppState>,
 ) -> bool {
-    let auth_config = &state.config.auth;
+    let config = state.config();
+    let auth_config = &config.auth;
   
 
     match auth_config.auth_type {
@@ -24,24 +24,47 @@ pub async fn is_authorized(
         }
     }
 }
+/// the api token the request was sent with, from the header or the `token` query parameter
+pub fn request_token(req: &HttpRequest) -> Option<String> {
+    if let Some(auth_header) = req.headers().get("Authorization")
+        && let Ok(auth_str) = auth_header.to_str()
+        && let Some(token) = auth_str.strip_prefix("Bearer ")
+    {
+        return Some(token.to_string());
+    }
+
+    let query_string = req.uri().query()?;
+    query_string.split('&')
+        .filter_map(|pair| pair.split_once('='))
+        .find(|(key, _)| *key == "token")
+        .map(|(_, value)| value.to_string())
+}
+
+/// short sha256 of a token, identifies the token in the build history without storing it
+pub fn token_fingerprint(token: &str) -> String {
+    openssl::sha::sha256(token.as_bytes())[..6]
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
 /// Check if the token is authorized
 fn check_token_auth(req: &HttpRequest, auth_config: &AuthConfig) -> bool {
-    if let Some(auth_header) = req.headers().get("Authorization") {
-        if let Ok(auth_str) = auth_header.to_str() {
-            if auth_str.starts_with("Bearer ") {
-                let token = &auth_str[7..];
-                return auth_config.allowed_tokens.contains(&token.to_string());
-            }
-        }
+    if let Some(auth_header) = req.headers().get("Authorization")
+        && let Ok(auth_str) = auth_header.to_str()
+        && let Some(token) = auth_str.strip_prefix("Bearer ")
+    {
+        return auth_config.allowed_tokens.contains(&token.to_string());
     }
 
     // Also check query parameter
     if let Some(query_string) = req.uri().query() {
         for pair in query_string.split('&') {
-            if let Some((key, value)) = pair.split_once('=') {
-                if key == "token" && auth_config.allowed_tokens.contains(&value.to_string()) {
-                    return true;
-                }
+            if let Some((key, value)) = pair.split_once('=')
+                && key == "token"
+                && auth_config.allowed_tokens.contains(&value.to_string())
+            {
+                return true;
             }
         }
     }
@@ -2,7 +2,7 @@ use std::collections::HashMap;
 
 use actix_web::{web, HttpRequest, HttpResponse, Responder};
 
-use crate::{auth::check_auth::is_authorized, models::{app_state::{AppState, BuildResponse}, status::Status}};
+use crate::{auth::check_auth::is_authorized, build::payload_files::discard_staged_uploads, journal::build_journal::save_journal, models::{app_state::{AppState, BuildRequest, BuildResponse}, cancel_token::CancelReason, status::Status}};
 
 
 /// abort a particular build
@@ -20,11 +20,12 @@ pub async fn abort(req: HttpRequest,payload: web::Json<HashMap<String, String>>,
         return HttpResponse::Unauthorized().json(res);
     }
 
-    let unique_id = payload.get(&state.config.project.build.unique_build_key);
+    let config = state.config();
+    let unique_id = payload.get(&config.project.build.unique_build_key);
 
     if unique_id.is_none() {
         let res = BuildResponse{
-            message: format!("Missing unique build key: {}", state.config.project.build.unique_build_key),
+            message: format!("Missing unique build key: {}", config.project.build.unique_build_key),
             status: Status::MissingUniqueId,
             build_id: None,
             token: None
@@ -32,25 +33,27 @@ pub async fn abort(req: HttpRequest,payload: web::Json<HashMap<String, String>>,
         return HttpResponse::BadRequest().json(res);
     }
 
-    let guard = state.builds.current_build.lock().await;
-
-    if let Some(current_build) = &*guard {
-        if &current_build.unique_id == unique_id.unwrap()  {
-            let mut is_terminated = state.is_terminated.lock().await;
-            *is_terminated = true;
-            println!("Terminating build");
+    let running_build_id = {
+        let current_builds = state.builds.current_builds.lock().await;
+        current_builds.values()
+            .find(|build| &build.unique_id == unique_id.unwrap())
+            .map(|build| build.id.clone())
+    };
 
-            let res = BuildResponse{
-                message: "Aborted".to_string(),
-                status: Status::Aborted,
-                build_id: Some( current_build.id.clone() ),
-                token: None
-            };
-            return HttpResponse::Ok().json(res);
+    if let Some(build_id) = running_build_id {
+        if let Some(handle) = state.builds.build_handles.lock().await.get(&build_id) {
+            handle.cancel.cancel(CancelReason::Aborted);
         }
-    }
+        println!("Terminating build {}", build_id);
 
-    drop(guard);
+        let res = BuildResponse{
+            message: "Aborted".to_string(),
+            status: Status::Aborted,
+            build_id: Some( build_id ),
+            token: None
+        };
+        return HttpResponse::Ok().json(res);
+    }
 
     let mut queue = state.builds.build_queue.lock().await;
     if let Some(index) = queue.iter().position(|build| {
@@ -58,6 +61,9 @@ pub async fn abort(req: HttpRequest,payload: web::Json<HashMap<String, String>>,
     }) {
         let id = queue.get(index).unwrap().id.clone();
         queue.remove(index);
+        drop(queue);
+        save_journal(&state).await;
+        discard_staged_uploads(&state.config().journal_path, &id).await;
         let res = BuildResponse{
             message: "Aborted".to_string(),
             status: Status::Aborted,
@@ -95,14 +101,18 @@ pub async fn abort_all(req: HttpRequest,state: web::Data<AppState>,)-> impl Resp
     }
 
     {
-        let mut is_terminated = state.is_terminated.lock().await;
-        *is_terminated = true;
-
+        let build_handles = state.builds.build_handles.lock().await;
+        for handle in build_handles.values() {
+            handle.cancel.cancel(CancelReason::Aborted);
+        }
     }
 
    
-    let mut queue = state.builds.build_queue.lock().await;
-    queue.clear();
+    let dropped: Vec<BuildRequest> = state.builds.build_queue.lock().await.drain(..).collect();
+    save_journal(&state).await;
+    for build in dropped {
+        discard_staged_uploads(&state.config().journal_path, &build.id).await;
+    }
 
     let res = BuildResponse{
         message: "Aborted".to_string(),
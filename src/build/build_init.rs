@@ -2,15 +2,44 @@
 use actix_web::{HttpRequest, HttpResponse, Responder,  web};
 use uuid::Uuid;
 
-use std::{collections::HashMap};
+use std::{collections::{BTreeMap, HashMap}, path::PathBuf};
 
-use crate::{auth::check_auth::is_authorized, build::build_manager::{ build_manager}, helpers::utils::{create_file_with_dirs_and_content, generate_token, secure_join_path}, models::{app_state::{AppState,  BuildRequest, BuildResponse,  ChannelMessage, ProjectLog}, config::{ PayloadType}, status::Status}};
+use crate::{auth::check_auth::{is_authorized, request_token, token_fingerprint}, journal::build_journal::{save_journal, staged_uploads_dir}, build::{build_manager::start_build_manager, payload_files::{discard_staged_uploads, payload_file_paths, replace_staged_uploads, stage_uploads}}, helpers::{payload_schema::{validate_payload, PayloadError}, upload::{read_build_body, BodyError, BuildBody, UploadedFile}, utils::generate_token}, models::{app_state::{AppState,  BuildRequest, BuildResponse,  ChannelMessage, DuplicateResponse, ProjectLog, ValidationResponse}, cancel_token::CancelReason, config::{BuildPriority, DuplicatePolicy}, status::Status}};
 
 
+/// the priority from the `priority` field, else the default of the api token
+fn request_priority(state: &AppState, payload: &HashMap<String, String>, token: Option<&str>) -> Result<BuildPriority, PayloadError> {
+    if let Some(priority) = payload.get("priority") {
+        return priority.parse().map_err(|_| PayloadError {
+            key: "priority".to_string(),
+            message: format!("'{}' is not one of low, normal, high", priority),
+        });
+    }
+
+    let config = state.config();
+    Ok(token
+        .and_then(|token| config.auth.token_priorities.get(token))
+        .copied()
+        .unwrap_or_default())
+}
+
+/// response of a build request while the server shuts down
+fn shutting_down() -> HttpResponse {
+    let res = BuildResponse{
+        message: "Server is shutting down".to_string(),
+        status: Status::ShuttingDown,
+        build_id: None,
+        token: None
+    };
+    HttpResponse::ServiceUnavailable().json(res)
+}
+
 /// Initialize a build
+/// the body is a json object of strings or `multipart/form-data`, `file` payloads can be uploaded
+/// as file parts or sent base64 encoded in a `<key>.base64` json field
 pub async fn build_initialize(
     req: HttpRequest,
-    payload: web::Json<HashMap<String, String>>,
+    body: web::Payload,
     state: web::Data<AppState>,
 ) -> impl Responder {
 
@@ -27,6 +56,29 @@ pub async fn build_initialize(
         return HttpResponse::Unauthorized().json(res);
     }
 
+    if *state.is_shutting_down.lock().await {
+        return shutting_down();
+    }
+
+    let config = state.config();
+    let build_body = read_build_body(&req, body, &config.project.build.payload, config.project.max_upload_size).await;
+    let BuildBody { mut payload, files } = match build_body {
+        Ok(build_body) => build_body,
+        Err(e) => {
+            let res = BuildResponse{
+                message: e.to_string(),
+                status: Status::InvalidPayload,
+                build_id: None,
+                token: None
+            };
+            return match e {
+                BodyError::TooLarge(_) => HttpResponse::PayloadTooLarge().json(res),
+                BodyError::Invalid(_) => HttpResponse::BadRequest().json(res),
+                BodyError::Storage(_) => HttpResponse::InternalServerError().json(res),
+            };
+        }
+    };
+
     {
         let project_token = payload.get("project_token");
         if project_token.is_none() {
@@ -42,136 +94,296 @@ pub async fn build_initialize(
 
     }
 
-    let unique_id = payload.get(&state.config.project.build.unique_build_key);
+    let mut errors = validate_payload(&config.project.build.payload, &mut payload).err().unwrap_or_default();
 
-    if unique_id.is_none() {
-        let res = BuildResponse{
-            message: format!("Missing unique build key: {}", state.config.project.build.unique_build_key),
-            status: Status::MissingUniqueId,
-            build_id: None,
-            token: None
+    let token = request_token(&req);
+    let priority = match request_priority(&state, &payload, token.as_deref()) {
+        Ok(priority) => priority,
+        Err(e) => {
+            errors.push(e);
+            BuildPriority::default()
+        }
+    };
+
+    if !errors.is_empty() {
+        let res = ValidationResponse{
+            message: "Invalid payload".to_string(),
+            status: Status::InvalidPayload,
+            errors,
         };
-        return HttpResponse::BadRequest().json(res);
+        return HttpResponse::UnprocessableEntity().json(res);
     }
 
-    for reqired_payload in &state.config.project.build.payload {
-        if ! payload.contains_key(&reqired_payload.key1) {
-            let res = BuildResponse{
-                message: format!("Missing payload key: {}", reqired_payload.key1),
-                status: Status::MissingPayload,
-                build_id: None,
-                token: None
-            };
-            return HttpResponse::BadRequest().json(res);
-        }//if key not found
+    let origin = BuildOrigin{
+        token,
+        priority,
+        retry_of: None,
+        start_step: 1,
+        step_outputs: BTreeMap::new(),
+        reuse_workspace: false,
+        kept_uploads: Vec::new(),
+    };
+
+    enqueue_build(&state, payload, files, origin).await
+}
+
+/// where a build to queue comes from, besides its payload
+pub struct BuildOrigin {
+    /// api token of the request
+    pub token: Option<String>,
+    pub priority: BuildPriority,
+    /// the finished build this one retries
+    pub retry_of: Option<String>,
+    /// first command to run, 1 based
+    pub start_step: usize,
+    /// values extracted by the steps before `start_step`
+    pub step_outputs: BTreeMap<usize, HashMap<String, String>>,
+    /// keep the `file` payloads already in the project instead of writing them again
+    pub reuse_workspace: bool,
+    /// uploads of a reused workspace, still listed as uploaded when no copy of them was kept
+    pub kept_uploads: Vec<String>,
+}
+
+/// uploads staged for a build that is not queued yet, removed unless it is queued
+struct StagedUploads(Option<PathBuf>);
+
+impl StagedUploads {
+    /// the build is queued, its uploads stay until it starts
+    fn queued(mut self) {
+        self.0 = None;
+    }
+}
+
+impl Drop for StagedUploads {
+    fn drop(&mut self) {
+        if let Some(dir) = self.0.take() {
+            let _ = std::fs::remove_dir_all(dir);
+        }
+    }
+}
+
+/// response of a `file` payload that cannot be written
+fn file_create_failed(message: String) -> HttpResponse {
+    let res = BuildResponse{
+        message,
+        status: Status::FileCreateFailed,
+        build_id: None,
+        token: None
+    };
+    HttpResponse::BadRequest().json(res)
+}
+
+/// queue a validated build: apply the duplicate policy, stage the uploads and start the manager
+/// the `file` payloads are checked here and written into the project when the build starts
+pub async fn enqueue_build(
+    state: &web::Data<AppState>,
+    payload: HashMap<String, String>,
+    files: HashMap<String, UploadedFile>,
+    origin: BuildOrigin,
+) -> HttpResponse {
+
+    if *state.is_shutting_down.lock().await {
+        return shutting_down();
     }
 
+    let config = state.config();
 
+    let unique_id = payload.get(&config.project.build.unique_build_key);
 
-    if state.config.project.max_pending_build == state.builds.build_queue.lock().await.len() as u32{
+    if unique_id.is_none() {
         let res = BuildResponse{
-            message: format!("Max Pending Reached: {}", state.config.project.max_pending_build),
+            message: format!("Missing unique build key: {}", config.project.build.unique_build_key),
+            status: Status::MissingUniqueId,
             build_id: None,
-            token: None,
-            status: Status::MaxPending,
+            token: None
         };
-        return HttpResponse::Conflict().json(res);
+        return HttpResponse::BadRequest().json(res);
     }
 
-    let mut is_already_running = false;
-    let guard = state.builds.current_build.lock().await;
 
-    if let Some(current_build) = &*guard {
-        if &current_build.unique_id == unique_id.unwrap()  {
-            let res = BuildResponse{
-            message: format!("Build already in progress: {}", current_build.unique_id),
-            build_id: Some(current_build.id.clone()),
-            token: Some( current_build.socket_token.clone() ),
-            status: Status::AlreadyBuilding,
+
+    let policy = config.project.duplicate_policy;
+
+    let current_builds = state.builds.current_builds.lock().await;
+    let running_build = current_builds.values()
+        .find(|build| &build.unique_id == unique_id.unwrap())
+        .map(|build| (build.id.clone(), build.socket_token.clone()));
+    let running_count = current_builds.len();
+    drop(current_builds);
+
+    if let Some((running_id, running_token)) = &running_build
+        && policy == DuplicatePolicy::Reject
+    {
+        let res = DuplicateResponse{
+            response: BuildResponse{
+                message: format!("Build already in progress: {}", unique_id.unwrap()),
+                build_id: Some(running_id.clone()),
+                token: Some(running_token.clone()),
+                status: Status::AlreadyBuilding,
+            },
+            policy,
+            previous_build_id: running_id.clone(),
         };
-            return HttpResponse::Conflict().json(res);
-        }
-        is_already_running = true;
+        return HttpResponse::Conflict().json(res);
     }//if current build exists
 
-    drop(guard);
+    // uploads wait next to the journal until the build starts, moved there before the queue is locked
+    let staged_id = Uuid::new_v4().to_string();
+    let staged = match staged_uploads_dir(&config.journal_path, &staged_id) {
+        Ok(dir) => StagedUploads(Some(dir)),
+        Err(e) => return file_create_failed(format!("Failed to create payload file: {}", e)),
+    };
+    if let Some(dir) = &staged.0
+        && let Err(e) = stage_uploads(dir, &files).await
+    {
+        return file_create_failed(format!("Failed to create payload file: {}", e));
+    }
 
     let mut build_queue = state.builds.build_queue.lock().await;
-    
-    let is_already_queued = build_queue.iter().any(|build| {
+
+    let queued_index = build_queue.iter().position(|build| {
         &build.unique_id == unique_id.unwrap()
     });
 
-    if is_already_queued {
+    if let Some(index) = queued_index
+        && policy == DuplicatePolicy::Reject
+    {
+        let res = DuplicateResponse{
+            response: BuildResponse{
+                message: "Build already in queue".to_string(),
+                token: None,
+                build_id: None,
+                status: Status::AlreadyQueue,
+            },
+            policy,
+            previous_build_id: build_queue[index].id.clone(),
+        };
+        return HttpResponse::Conflict().json(res);
+    }
+
+    // a replaced build keeps its place, everything else needs a free spot in the queue
+    let is_replacing = queued_index.is_some() && policy == DuplicatePolicy::Replace;
+    let is_dropping_queued = queued_index.is_some() && policy == DuplicatePolicy::CancelRunningAndRestart;
+    if !is_replacing && !is_dropping_queued && config.project.max_pending_build <= build_queue.len() as u32 {
         let res = BuildResponse{
-            message: format!("Build already in queue"),
-            token: None,
+            message: format!("Max Pending Reached: {}", config.project.max_pending_build),
             build_id: None,
-            status: Status::AlreadyQueue,
+            token: None,
+            status: Status::MaxPending,
         };
         return HttpResponse::Conflict().json(res);
     }
 
+    // a replaced build keeps its id and socket token, so listeners of the queued build stay attached
+    let (id, new_token) = match queued_index {
+        Some(index) if is_replacing => (build_queue[index].id.clone(), build_queue[index].socket_token.clone()),
+        _ => (staged_id.clone(), generate_token(32)),
+    };
 
-    for reqired_payload in &state.config.project.build.payload {
-        if reqired_payload.r#type != PayloadType::File{
-            continue;
-        }//continue if not file
-        let file_path = if reqired_payload.key2.is_some() {
-            reqired_payload.key2.as_ref().unwrap()
-        } else {
-            reqired_payload.key1.as_str()
-        };
-
-        // let path_relative = format!("{}/{}", state.config.project.project_path, file_path);
+    // the files are written when the build starts, a build of the same key may still be running now
+    if !origin.reuse_workspace
+        && let Err(e) = payload_file_paths(&config, &payload, &id, unique_id.unwrap())
+    {
+        return file_create_failed(e);
+    }
 
-        let path_relative = secure_join_path(&state.config.project.project_path, &file_path);
-        if path_relative.is_none(){
-            let res = BuildResponse{
-                message: format!("Failed to create payload file: Path is not secure"),
-                status: Status::FileCreateFailed,
-                build_id: None,
-                token: None
-            };
-            return HttpResponse::BadRequest().json(res);
+    // a reused workspace lists its uploads even when no copy of them was kept
+    let mut uploaded_files: Vec<String> = files.keys().cloned().collect();
+    for key in origin.kept_uploads {
+        if !uploaded_files.contains(&key) {
+            uploaded_files.push(key);
         }
+    }
 
-        let path_relative = path_relative.unwrap();
-
-        let create_path = create_file_with_dirs_and_content(&path_relative, payload.get(&reqired_payload.key1).unwrap().as_str());
-        if let Err(e) = create_path {
-            let res = BuildResponse{
-                message: format!("Failed to create payload file: {}", e),
-                status: Status::FileCreateFailed,
-                build_id: None,
-                token: None
-            };
-            return HttpResponse::BadRequest().json(res);
+    if let Some(index) = queued_index
+        && is_replacing
+    {
+        if let Err(e) = replace_staged_uploads(&config.journal_path, &staged_id, &id) {
+            return file_create_failed(format!("Failed to create payload file: {}", e));
         }
-        
+        staged.queued();
+
+        let queued_build = &mut build_queue[index];
+        queued_build.payload = payload.clone();
+        queued_build.triggered_by = origin.token.as_deref().map(token_fingerprint);
+        queued_build.priority = origin.priority;
+        queued_build.uploaded_files = uploaded_files;
+        queued_build.reuse_workspace = origin.reuse_workspace;
+        queued_build.retry_of = origin.retry_of;
+        queued_build.start_step = origin.start_step;
+        queued_build.step_outputs = origin.step_outputs;
+        drop(build_queue);
+        save_journal(state).await;
+
+        println!("Replaced the payload of the queued build for {}", unique_id.unwrap());
+
+        let res = DuplicateResponse{
+            response: BuildResponse{
+                message: "Queued build replaced".to_string(),
+                token: Some(new_token),
+                build_id: Some(id.clone()),
+                status: Status::Replaced,
+            },
+            policy,
+            previous_build_id: id,
+        };
+        return HttpResponse::Ok().json(res);
     }
-   
 
-    let new_token = generate_token(32);
-    let id = Uuid::new_v4();
+    let dropped_build_id = match queued_index {
+        Some(index) if is_dropping_queued => Some(build_queue.remove(index).id),
+        _ => None,
+    };
 
     let build_state =  BuildRequest{
-        id: id.to_string(),
+        id: id.clone(),
         unique_id: unique_id.unwrap().to_string(),
         payload: payload.clone(),
         socket_token: new_token.clone(),
+        queued_at: chrono::Utc::now(),
+        triggered_by: origin.token.as_deref().map(token_fingerprint),
+        priority: origin.priority,
+        skipped: 0,
+        pinned: false,
+        uploaded_files,
+        reuse_workspace: origin.reuse_workspace,
+        retry_of: origin.retry_of,
+        start_step: origin.start_step,
+        step_outputs: origin.step_outputs,
     };
 
+    // the build waits when every slot is busy, older builds are still queued, the queue is paused
+    // or a build of the same key is still running
+    let is_already_running = running_count + build_queue.len() >= config.project.build_slots()
+        || *state.builds.paused.lock().await
+        || running_build.is_some();
+
     build_queue.push(build_state);
     drop(build_queue);
+    staged.queued();
+
+    if let Some(dropped_build_id) = &dropped_build_id {
+        discard_staged_uploads(&config.journal_path, dropped_build_id).await;
+    }
+
+    if let Some((running_id, _)) = &running_build
+        && policy == DuplicatePolicy::CancelRunningAndRestart
+    {
+        if let Some(handle) = state.builds.build_handles.lock().await.get(running_id) {
+            handle.cancel.cancel(CancelReason::Aborted);
+        }
+        println!("Terminating build {} for a restart", running_id);
+    }
+
+    save_journal(state).await;
 
     let project_log = ProjectLog{
-        id: id.to_string(),
+        id: id.clone(),
         unique_id: unique_id.unwrap().to_string(),
         socket_token: new_token.clone(),
         step: 0,
         timestamp: chrono::Utc::now(),
-        state: if !*state.is_queue_running.lock().await {Status::Building} else {Status::Pending},
+        state: if is_already_running {Status::Pending} else {Status::Building},
         message: "In Queue".to_string()
     
     };
@@ -187,23 +399,24 @@ pub async fn build_initialize(
     println!("Starting build manager to handle build for {}", unique_id.unwrap());
 
 
-    if !*state.is_queue_running.lock().await{
-
-
-        tokio::spawn(async move {
-            build_manager(state.clone()).await;
-        });
-    }
+    start_build_manager(state.clone()).await;
      
     let res = BuildResponse{
         message:  if is_already_running {"Build is in pending state".to_string()} else {"Build started".to_string()},
         token: Some(new_token.clone()),
-        build_id: Some( id.to_string() ),
+        build_id: Some( id.clone() ),
         status:if is_already_running {Status::Pending} else {Status::Building},
     }; //need to handle here
 
-    
-
+    // report the policy whenever a build of the same key was around
+    if let Some(previous_build_id) = dropped_build_id.or(running_build.map(|(running_id, _)| running_id)) {
+        let res = DuplicateResponse{
+            response: res,
+            policy,
+            previous_build_id,
+        };
+        return HttpResponse::Ok().json(res);
+    }
 
     HttpResponse::Ok().json(res)
-}
\ No newline at end of file
+}
@@ -1,142 +1,363 @@
-use std::{collections::HashMap};
+use std::collections::HashSet;
 
 use actix_web::web;
+use tokio::task::JoinSet;
 
-use crate::{error_success::handle_error_success::{ handle_error_success}, models::{app_state::{ AppState, BuildProcess, ChannelMessage, ProjectLog}, status::Status}};
+use crate::{build::payload_files::{finish_staged_uploads, write_payload_files}, helpers::utils::{builder_output_dir, push_build_log, OutputTarget}, history::history_store::record_build, journal::build_journal::save_journal, error_success::handle_error_success::{ handle_error_success}, models::{app_state::{ AppState, BuildHandle, BuildProcess, BuildRequest, ChannelMessage, ProjectLog}, cancel_token::CancelReason, status::Status}, shutdown::graceful_shutdown::SHUTDOWN_REASON};
 
 use super::run_build::run_build;
 
+/// spawn the build manager unless it is already running
+pub async fn start_build_manager(state: web::Data<AppState>) {
+    let mut is_queue_running = state.is_queue_running.lock().await;
+    if *is_queue_running {
+        state.builds.queue_notify.notify_one();
+        return;
+    }
+    *is_queue_running = true;
+    drop(is_queue_running);
+
+    tokio::spawn(async move {
+        build_manager(state).await;
+    });
+}
+
 /// hanldes the builds queue and execution
+/// keeps up to `build_slots` builds running, each one on its own task
 pub async fn build_manager(state: web::Data<AppState>) {
     
     {
         let mut is_queue_running = state.is_queue_running.lock().await;
         *is_queue_running = true;
     }
-   
+
+    let mut workers = JoinSet::new();
 
     loop{
-        let mut build_queue = state.builds.build_queue.lock().await;
-        
-        if build_queue.is_empty() {
-            break;
+
+        // a reloaded config applies from the next round on
+        let config = state.config();
+        let slots = config.project.build_slots();
+
+        // fill the free slots from the queue, unless it is paused or the server shuts down
+        while workers.len() < slots && !*state.builds.paused.lock().await && !*state.is_shutting_down.lock().await {
+            let running: HashSet<String> = state.builds.current_builds.lock().await
+                .values()
+                .map(|build| build.unique_id.clone())
+                .collect();
+
+            let mut build_queue = state.builds.build_queue.lock().await;
+            let Some(index) = next_build_index(&mut build_queue, &running, config.project.max_priority_skips) else {
+                break;
+            };
+            let build = build_queue.remove(index);
+            drop(build_queue);
+
+            let handle = start_build(&state, &build).await;
+            workers.spawn(execute_build(state.clone(), build, handle));
         }
 
-        let build = build_queue.remove(0);
-        drop(build_queue);
-
-        let build_process = BuildProcess{
-            id: build.id.clone(),
-            unique_id: build.unique_id.clone(),
-            status: crate::models::status::Status::Building,
-            current_step: 1,
-            total_steps: state.config.project.build.commands.len() as usize,
-            started_at: chrono::Utc::now(),
-            end_at: chrono::Utc::now(),
-            duration:0,
-            socket_token: build.socket_token.clone(),
-            logs: Vec::new(),
-            payload: build.payload.clone(),
-            out_payload: HashMap::new(),
-        };
-        println!("Starting build for {}", build.unique_id);
+        if workers.is_empty() {
+            // stop only when nothing is queued, the queue is paused or the server shuts down, checked under
+            // the queue lock so a build pushed right now either is seen here or starts a new manager
+            let build_queue = state.builds.build_queue.lock().await;
+            if build_queue.is_empty() || *state.builds.paused.lock().await || *state.is_shutting_down.lock().await {
+                *state.is_queue_running.lock().await = false;
+                break;
+            }
+            continue;
+        }
 
-        {
-            state.builds.current_build.lock().await.replace(build_process);
+        tokio::select! {
+            _ = workers.join_next() => {
+                let is_queue_empty = state.builds.build_queue.lock().await.is_empty();
+                if !is_queue_empty && config.project.next_build_delay > 0 {
+                    println!("Sleeping for {} seconds", config.project.next_build_delay);
+                    tokio::time::sleep(std::time::Duration::from_secs(config.project.next_build_delay as u64)).await;
+                }
+            }
+            _ = state.builds.queue_notify.notified() => {}
         }
 
-        // start the thread to perform the build operation here
-        // and await
-        let project_log = ProjectLog{
-            id: build.id.clone(),
-            unique_id: build.unique_id.clone(),
-            socket_token: build.socket_token.clone(),
-            step: 0,
-            timestamp: chrono::Utc::now(),
-            state: Status::Building,
-            message: "Starting build".to_string()
-        };
+    }//loop forever(or until shutdown)
+
+    
+    {
+        let mut project_logs = state.project_logs.lock().await;
+        project_logs.clear();
+    }
+
+    println!("Bulid manager ended! Nothing to do.");
+
 
+}
 
-        
-        {
-            let mut project_logs = state.project_logs.lock().await;
-            project_logs.push(project_log.clone());
-            let log = serde_json::to_string(&project_log).unwrap();
-            let _ = state.project_sender.send(ChannelMessage::Data(log));
+/// which queued build starts next: the first pinned one, else the oldest one of the highest priority,
+/// unless an older build was skipped `max_skips` times already, then that one
+/// builds of a unique key that is still running wait, they neither start nor count skips
+/// every older build the chosen one jumps over counts one more skip
+fn next_build_index(queue: &mut [BuildRequest], running: &HashSet<String>, max_skips: u32) -> Option<usize> {
+    let is_ready = |build: &BuildRequest| !running.contains(&build.unique_id);
+
+    if let Some(index) = queue.iter().position(|build| is_ready(build) && build.pinned) {
+        for build in queue[..index].iter_mut().filter(|build| !running.contains(&build.unique_id)) {
+            build.skipped += 1;
         }
+        return Some(index);
+    }
 
+    if max_skips == 0 {
+        return queue.iter().position(is_ready);
+    }
 
-        run_build(state.clone()).await;
+    let index = match queue.iter().position(|build| is_ready(build) && build.skipped >= max_skips) {
+        Some(index) => index,
+        None => {
+            let highest = queue.iter().filter(|build| is_ready(build)).map(|build| build.priority).max()?;
+            queue.iter().position(|build| is_ready(build) && build.priority == highest)?
+        }
+    };
 
-        
-        
-        {
-            let mut terminated = state.is_terminated.lock().await;
-            *terminated = false;
+    for build in queue[..index].iter_mut().filter(|build| !running.contains(&build.unique_id)) {
+        build.skipped += 1;
+    }
+
+    Some(index)
+}
+
+/// the queue indexes in the order the builds would start if nothing else changed,
+/// builds held back by a running unique key come last in queue order
+pub fn start_order(queue: &[BuildRequest], running: &HashSet<String>, max_skips: u32) -> Vec<usize> {
+    let mut remaining: Vec<(usize, BuildRequest)> = queue.iter().cloned().enumerate().collect();
+    let mut order = Vec::with_capacity(queue.len());
+
+    loop {
+        let mut builds: Vec<BuildRequest> = remaining.iter().map(|(_, build)| build.clone()).collect();
+        let Some(index) = next_build_index(&mut builds, running, max_skips) else {
+            break;
+        };
+        for ((_, build), simulated) in remaining.iter_mut().zip(builds) {
+            build.skipped = simulated.skipped;
         }
+        order.push(remaining.remove(index).0);
+    }
+
+    order.extend(remaining.into_iter().map(|(index, _)| index));
+    order
+}
+
+/// register a dequeued build as running
+async fn start_build(state: &web::Data<AppState>, build: &BuildRequest) -> BuildHandle {
+
+    // the build keeps this config until it is done, whatever is reloaded meanwhile
+    let config = state.config();
+    let build_process = BuildProcess::new(build, config.project.build.commands.len());
+    println!("Starting build for {}", build.unique_id);
+
+    let handle = BuildHandle::new(config);
 
-        //check the status of the build whether its failed or success
-        {
-            let mut current_build = state.builds.current_build.lock().await;
-        
-            let cur_build = current_build.as_mut().unwrap();
+    state.builds.current_builds.lock().await.insert(build.id.clone(), build_process);
+    state.builds.build_handles.lock().await.insert(build.id.clone(), handle.clone());
+    save_journal(state).await;
+
+    let project_log = ProjectLog{
+        id: build.id.clone(),
+        unique_id: build.unique_id.clone(),
+        socket_token: build.socket_token.clone(),
+        step: 0,
+        timestamp: chrono::Utc::now(),
+        state: Status::Building,
+        message: "Starting build".to_string()
+    };
+
+    {
+        let mut project_logs = state.project_logs.lock().await;
+        project_logs.push(project_log.clone());
+        let log = serde_json::to_string(&project_log).unwrap();
+        let _ = state.project_sender.send(ChannelMessage::Data(log));
+    }
+
+    handle
+}
+
+/// fail a build whose `file` payloads could not be written, no command is run
+async fn fail_payload_files(state: &web::Data<AppState>, build_id: &str, handle: &BuildHandle, message: String) {
+    let target = OutputTarget {
+        state,
+        build_id,
+        handle,
+        step: 0,
+        attempt: 1,
+        send_to_sock: true,
+        bypass_termination: false,
+    };
+    push_build_log(&target, Status::FileCreateFailed, message).await;
+
+    if let Some(current_build) = state.builds.current_builds.lock().await.get_mut(build_id) {
+        current_build.status = Status::Error;
+    }
+}
+
+/// write the `file` payloads, run a single build to the end and report it
+async fn execute_build(state: web::Data<AppState>, build: BuildRequest, handle: BuildHandle) {
+    let build_id = build.id.clone();
+
+    match write_payload_files(&handle.config, &build).await {
+        Ok(()) => run_build(state.clone(), build_id.clone(), handle.clone()).await,
+        Err(e) => fail_payload_files(&state, &build_id, &handle, e).await,
+    }
+
+    //check the status of the build whether its failed or success
+    let finished_build = {
+        let mut current_builds = state.builds.current_builds.lock().await;
+        current_builds.get_mut(&build_id).map(|cur_build| {
             cur_build.end_at = chrono::Utc::now();
             cur_build.duration = cur_build.end_at.signed_duration_since(cur_build.started_at).num_seconds();
-            let cur_build_clone = cur_build.clone();
+            cur_build.clone()
+        })
+    };
 
-            drop(current_build);
+    let mut is_failed = false;
+    if let Some(finished_build) = finished_build {
+        let finished_build = handle_error_success(state.clone(), &handle.config, finished_build).await;
+        record_build(&state, &finished_build);
+        is_failed = finished_build.status != Status::Success;
+    }
 
-            handle_error_success(state.clone(),cur_build_clone.clone()).await;
+    let reason = match handle.cancel.reason() {
+        Some(CancelReason::Shutdown) => SHUTDOWN_REASON,
+        _ => "Build finished",
+    };
+    let _ = handle.sender.send(ChannelMessage::Shutdown(reason.to_string()));
 
-            
-            let _ = state.build_sender.send(ChannelMessage::Shutdown);
-        }
+    state.builds.current_builds.lock().await.remove(&build_id);
+    state.builds.build_handles.lock().await.remove(&build_id);
+    save_journal(&state).await;
 
-        {
-            let mut current_build = state.builds.current_build.lock().await;
-        
-            *current_build = None;
-        }
-            
+    if let Ok(dir) = builder_output_dir(&build_id) {
+        let _ = std::fs::remove_dir_all(dir);
+    }
+    finish_staged_uploads(&state, &build_id, &build.uploaded_files, is_failed).await;
+}
 
-        {
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::config::BuildPriority;
 
-            let build_queue = state.builds.build_queue.lock().await;
-            
-            if build_queue.is_empty() {
-                break;
-            }
+    /// a queued build, its id is its unique key
+    fn queued(unique_id: &str, priority: BuildPriority) -> BuildRequest {
+        serde_json::from_value(serde_json::json!({
+            "id": unique_id,
+            "unique_id": unique_id,
+            "payload": {},
+            "socket_token": "",
+            "priority": priority,
+        })).unwrap()
+    }
 
+    /// the unique keys in the order the builds are started, when nothing else is running
+    fn started(mut queue: Vec<BuildRequest>, max_skips: u32) -> Vec<String> {
+        let mut order = Vec::new();
+        while let Some(index) = next_build_index(&mut queue, &HashSet::new(), max_skips) {
+            order.push(queue.remove(index).unique_id);
         }
+        order
+    }
 
-            
-            if state.config.project.next_build_delay > 0 {
-                println!("Sleeping for {} seconds", state.config.project.next_build_delay);
-                tokio::time::sleep(std::time::Duration::from_secs(state.config.project.next_build_delay as u64)).await;
-            }
-
-        
+    #[test]
+    fn starts_the_highest_priority_first_then_in_order() {
+        let queue = vec![
+            queued("a", BuildPriority::Normal),
+            queued("b", BuildPriority::Low),
+            queued("c", BuildPriority::High),
+            queued("d", BuildPriority::Normal),
+            queued("e", BuildPriority::High),
+        ];
+        assert_eq!(started(queue, 10), ["c", "e", "a", "d", "b"]);
+    }
 
-    }//loop forever(or until shutdown)
+    #[test]
+    fn starts_a_low_priority_build_after_max_skips() {
+        let mut queue = vec![queued("low", BuildPriority::Low)];
+        queue.extend((0..5).map(|index| queued(&format!("high{}", index), BuildPriority::High)));
+        assert_eq!(started(queue, 2), ["high0", "high1", "low", "high2", "high3", "high4"]);
+    }
 
-    
- 
-    {
-        let mut is_queue_running = state.is_queue_running.lock().await;
-        *is_queue_running = false;
+    #[test]
+    fn counts_a_skip_for_every_older_build_jumped_over() {
+        let mut queue = vec![
+            queued("a", BuildPriority::Low),
+            queued("b", BuildPriority::Normal),
+            queued("c", BuildPriority::High),
+        ];
+        assert_eq!(next_build_index(&mut queue, &HashSet::new(), 5), Some(2));
+        assert_eq!(queue.iter().map(|build| build.skipped).collect::<Vec<_>>(), [1, 1, 0]);
     }
-    
-    {
-        let mut project_logs = state.project_logs.lock().await;
-        project_logs.clear();
+
+    #[test]
+    fn holds_back_running_unique_keys_without_counting_skips() {
+        let mut queue = vec![
+            queued("busy", BuildPriority::High),
+            queued("a", BuildPriority::Low),
+            queued("b", BuildPriority::Normal),
+        ];
+        let running = HashSet::from(["busy".to_string()]);
+
+        assert_eq!(next_build_index(&mut queue, &running, 5), Some(2));
+        assert_eq!(queue[0].skipped, 0);
+        assert_eq!(queue[1].skipped, 1);
+
+        // even once it skipped enough, a running key does not start
+        queue[0].skipped = 5;
+        queue.remove(2);
+        assert_eq!(next_build_index(&mut queue, &running, 5), Some(1));
+
+        queue.remove(1);
+        assert_eq!(next_build_index(&mut queue, &running, 5), None);
+        assert_eq!(next_build_index(&mut queue, &running, 0), None);
     }
 
-    //delte all the logs once get
-    // let mut build_queue = state.builds.build_queue.lock().await;
-    // *build_queue = Vec::new();
+    #[test]
+    fn ignores_priorities_without_skips() {
+        let queue = vec![
+            queued("a", BuildPriority::Low),
+            queued("b", BuildPriority::High),
+            queued("c", BuildPriority::Normal),
+        ];
+        assert_eq!(started(queue, 0), ["a", "b", "c"]);
 
-    println!("Bulid manager ended! Nothing to do.");
+        let mut queue = vec![queued("busy", BuildPriority::Low), queued("a", BuildPriority::Low)];
+        let running = HashSet::from(["busy".to_string()]);
+        assert_eq!(next_build_index(&mut queue, &running, 0), Some(1));
+    }
 
+    #[test]
+    fn starts_a_pinned_build_first_whatever_its_priority() {
+        let mut queue = vec![
+            queued("a", BuildPriority::High),
+            queued("b", BuildPriority::Low),
+            queued("c", BuildPriority::Normal),
+        ];
+        queue[1].pinned = true;
+        assert_eq!(started(queue, 2), ["b", "a", "c"]);
+    }
 
-}
\ No newline at end of file
+    #[test]
+    fn lists_the_queue_in_start_order() {
+        let mut queue = vec![
+            queued("busy", BuildPriority::High),
+            queued("a", BuildPriority::Low),
+            queued("b", BuildPriority::Normal),
+            queued("c", BuildPriority::High),
+            queued("d", BuildPriority::Low),
+        ];
+        queue[4].pinned = true;
+        let running = HashSet::from(["busy".to_string()]);
+        assert_eq!(start_order(&queue, &running, 5), [4, 3, 2, 1, 0]);
+        // jumping over a, b and c made them reach the limit
+        assert_eq!(start_order(&queue, &running, 1), [4, 1, 2, 3, 0]);
+        // the listing does not count the skips
+        assert!(queue.iter().all(|build| build.skipped == 0));
+    }
+}
@@ -0,0 +1,120 @@
+use actix_web::{web, HttpRequest, HttpResponse, Responder};
+use serde::Deserialize;
+use serde_json::json;
+
+use crate::{auth::check_auth::is_authorized, helpers::redact::build_response, history::history_store::HistoryFilter, models::{app_state::{AppState, BuildProcess, BuildResponse}, status::Status}};
+
+#[derive(Deserialize)]
+pub struct BuildQuery {
+    /// include the build logs
+    #[serde(default)]
+    pub logs: bool,
+}
+
+#[derive(Deserialize)]
+pub struct FindBuildsQuery {
+    pub unique_id: String,
+    #[serde(default)]
+    pub logs: bool,
+}
+
+/// the running builds, then the queued ones
+async fn live_builds(state: &AppState) -> Vec<BuildProcess> {
+    let total_steps = state.config().project.build.commands.len();
+
+    let mut builds: Vec<BuildProcess> = state.builds.current_builds.lock().await.values().cloned().collect();
+
+    builds.extend(state.builds.build_queue.lock().await.iter().map(|build| BuildProcess::queued(build, total_steps)));
+
+    builds
+}
+
+/// response of a request that did not pass `is_authorized`
+pub fn unauthorized() -> HttpResponse {
+    let res = BuildResponse{
+        message: "Unauthorized Access".to_string(),
+        status: Status::Unauthorized,
+        build_id: None,
+        token: None
+    };
+    HttpResponse::Unauthorized().json(res)
+}
+
+/// get a queued, running or finished build by its id
+pub async fn get_build(
+    req: HttpRequest,
+    path: web::Path<String>,
+    query: web::Query<BuildQuery>,
+    state: web::Data<AppState>,
+) -> impl Responder {
+
+    if !is_authorized(&req, state.clone()).await {
+        return unauthorized();
+    }
+
+    let build_id = path.into_inner();
+    let mut build = live_builds(&state).await.into_iter().find(|build| build.id == build_id);
+
+    if build.is_none() {
+        build = match state.history.get(&build_id) {
+            Ok(build) => build,
+            Err(e) => {
+                println!("Failed to read the build history: {}", e);
+                None
+            }
+        };
+    }
+
+    match build {
+        Some(build) => HttpResponse::Ok().json(build_response(&state.config(), build, query.logs)),
+        None => {
+            let res = BuildResponse{
+                message: format!("Build not found: {}", build_id),
+                status: Status::NotFound,
+                build_id: Some(build_id),
+                token: None
+            };
+            HttpResponse::NotFound().json(res)
+        }
+    }
+}
+
+/// find the running, queued and latest finished builds of a unique key
+pub async fn find_builds(
+    req: HttpRequest,
+    query: web::Query<FindBuildsQuery>,
+    state: web::Data<AppState>,
+) -> impl Responder {
+
+    if !is_authorized(&req, state.clone()).await {
+        return unauthorized();
+    }
+
+    let mut builds: Vec<BuildProcess> = live_builds(&state).await
+        .into_iter()
+        .filter(|build| build.unique_id == query.unique_id)
+        .collect();
+
+    // the latest finished ones, the full list is paged through `/history`
+    let filter = HistoryFilter{
+        status: None,
+        unique_id: Some(query.unique_id.clone()),
+        from: None,
+        to: None,
+        triggered_by: None,
+        page: 1,
+        per_page: 100,
+    };
+    match state.history.list(&filter) {
+        Ok(page) => builds.extend(page.builds),
+        Err(e) => println!("Failed to read the build history: {}", e),
+    }
+
+    let config = state.config();
+    let builds: Vec<BuildProcess> = builds.into_iter().map(|build| build_response(&config, build, query.logs)).collect();
+
+    HttpResponse::Ok().json(json!({
+        "status": "success",
+        "builds": builds
+    }))
+}
@@ -0,0 +1,202 @@
+use std::{collections::HashMap, fs, future::Future, os::unix::fs::DirBuilderExt, path::PathBuf, pin::Pin, process::{ExitStatus, Stdio}, time::Duration};
+
+use tokio::{process::Command, sync::Notify, time::Instant};
+
+use crate::{helpers::{template::RenderedCommand, utils::{builder_output_dir, parse_builder_output, push_build_log, read_stderr, read_stdout, OutputTarget}}, models::status::Status};
+
+/// how a command ended
+pub enum CommandOutcome {
+    Exited(ExitStatus),
+    /// the build was cancelled and the command killed, the reason is on its `CancelToken`
+    Aborted,
+    /// the deadline passed and the command killed
+    TimedOut,
+    /// the command went quiet for longer than `no_output_timeout` and was killed
+    NoOutput,
+    /// the command could not be started at all
+    SpawnFailed(String),
+}
+
+impl CommandOutcome {
+    pub fn is_success(&self) -> bool {
+        matches!(self, CommandOutcome::Exited(status) if status.success())
+    }
+}
+
+/// send a signal to every process in the group led by `pid`
+fn signal_process_group(pid: u32, signal: i32) {
+    // SAFETY: killpg only sends a signal, a stale group id at worst returns ESRCH
+    unsafe {
+        libc::killpg(pid as i32, signal);
+    }
+}
+
+/// SIGTERM the process group, give it `grace` to exit, then SIGKILL it
+/// `run` is the future streaming the output and waiting on the child
+async fn terminate_process_group<F>(pid: Option<u32>, grace: Duration, mut run: Pin<&mut F>)
+where
+    F: Future,
+{
+    let Some(pid) = pid else {
+        return;
+    };
+
+    signal_process_group(pid, libc::SIGTERM);
+    if tokio::time::timeout(grace, run.as_mut()).await.is_ok() {
+        return;
+    }
+
+    println!("Process group {} ignored SIGTERM, killing it", pid);
+    signal_process_group(pid, libc::SIGKILL);
+
+    // a process that left the group can still hold the pipes open, don't wait on it forever
+    let _ = tokio::time::timeout(grace, run).await;
+}
+
+/// resolves at the deadline, never without one
+async fn wait_for_deadline(deadline: Option<Instant>) {
+    match deadline {
+        Some(deadline) => tokio::time::sleep_until(deadline).await,
+        None => std::future::pending().await,
+    }
+}
+
+/// resolves once `limit` passes without any output line, never without a limit
+async fn wait_for_silence(activity: &Notify, limit: Option<Duration>) {
+    let Some(limit) = limit else {
+        return std::future::pending().await;
+    };
+
+    while tokio::time::timeout(limit, activity.notified()).await.is_ok() {}
+}
+
+/// create an empty `$BUILDER_OUTPUT` file for the current step and attempt
+/// the directory is private as the outputs may hold secrets
+fn create_output_file(target: &OutputTarget<'_>) -> std::io::Result<PathBuf> {
+    let dir = builder_output_dir(target.build_id)?;
+    fs::DirBuilder::new().recursive(true).mode(0o700).create(&dir)?;
+
+    let path = dir.join(format!("step-{}-{}.env", target.step, target.attempt));
+    fs::File::create(&path)?;
+    Ok(path)
+}
+
+/// read the `KEY=value` lines the command wrote to `$BUILDER_OUTPUT`
+/// only keys listed in `extract_envs` are kept, they go into the env of the next steps and the build payload
+async fn import_outputs(target: &OutputTarget<'_>, path: &PathBuf, extract_envs: &[String], env_map: &mut HashMap<String, String>) {
+    let content = fs::read_to_string(path).unwrap_or_default();
+    let _ = fs::remove_file(path);
+
+    let outputs: Vec<(String, String)> = parse_builder_output(&content)
+        .into_iter()
+        .filter(|(key, _)| extract_envs.contains(key))
+        .collect();
+
+    if outputs.is_empty() {
+        return;
+    }
+
+    let mut current_builds = target.state.builds.current_builds.lock().await;
+    if let Some(build) = current_builds.get_mut(target.build_id) {
+        for (key, value) in &outputs {
+            build.payload.insert(key.clone(), value.clone());
+            build.outputs.insert(key.clone(), value.clone());
+            build.step_outputs.entry(target.step).or_default().insert(key.clone(), value.clone());
+        }
+    }
+    drop(current_builds);
+
+    env_map.extend(outputs);
+}
+
+/// run a command with bash in its own process group and stream its output to the build
+/// unless `target.bypass_termination` is set, cancelling the build kills the whole group
+/// the group is killed as well once `deadline` passes or the output stays silent for `no_output_timeout`
+/// values written to `$BUILDER_OUTPUT` are imported even when the command fails
+/// the placeholder values are set as env vars next to `env_map`, see `render_command`
+pub async fn execute_command(
+    target: &OutputTarget<'_>,
+    command: &RenderedCommand,
+    env_map: &mut HashMap<String, String>,
+    extract_envs: &[String],
+    deadline: Option<Instant>,
+    no_output_timeout: Option<Duration>,
+) -> CommandOutcome {
+
+    let output_file = match create_output_file(target) {
+        Ok(path) => path,
+        Err(e) => return CommandOutcome::SpawnFailed(format!("Failed to create the output file: {}", e)),
+    };
+
+    let child = Command::new("bash")
+        .arg("-c")
+        .arg(&command.command)
+        .envs(&*env_map)
+        .envs(command.params.iter().cloned())
+        .env("BUILDER_OUTPUT", &output_file)
+        .current_dir(target.handle.config.project.project_path.as_str())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .process_group(0)
+        .kill_on_drop(true)
+        .spawn();
+
+    let mut child = match child {
+        Ok(child) => child,
+        Err(e) => {
+            let _ = fs::remove_file(&output_file);
+            return CommandOutcome::SpawnFailed(e.to_string());
+        }
+    };
+
+    let pid = child.id();
+    let stdout = child.stdout.take().unwrap();
+    let stderr = child.stderr.take().unwrap();
+
+    let activity = Notify::new();
+
+    let run = async {
+        tokio::join!(
+            read_stdout(stdout, target, &activity),
+            read_stderr(stderr, target, &activity)
+        );
+        child.wait().await
+    };
+    tokio::pin!(run);
+
+    let abort = async {
+        if target.bypass_termination {
+            std::future::pending::<()>().await;
+        }
+        target.handle.cancel.cancelled().await;
+    };
+
+    let grace = Duration::from_secs(target.handle.config.project.abort_grace_period);
+
+    let outcome = tokio::select! {
+        status = &mut run => match status {
+            Ok(status) => CommandOutcome::Exited(status),
+            Err(e) => CommandOutcome::SpawnFailed(e.to_string()),
+        },
+        _ = abort => {
+            terminate_process_group(pid, grace, run.as_mut()).await;
+            CommandOutcome::Aborted
+        }
+        _ = wait_for_deadline(deadline) => {
+            terminate_process_group(pid, grace, run.as_mut()).await;
+            CommandOutcome::TimedOut
+        }
+        _ = wait_for_silence(&activity, no_output_timeout) => {
+            // the warning always reaches the socket, even for quiet commands
+            let warning_target = OutputTarget { send_to_sock: true, ..*target };
+            push_build_log(&warning_target, Status::Warning, format!("No output for {}s, killing the command", no_output_timeout.unwrap_or_default().as_secs())).await;
+
+            terminate_process_group(pid, grace, run.as_mut()).await;
+            CommandOutcome::NoOutput
+        }
+    };
+
+    import_outputs(target, &output_file, extract_envs, env_map).await;
+
+    outcome
+}
@@ -1,4 +1,8 @@
 pub mod build_init;
 pub mod build_manager;
 pub mod run_build;
-pub mod abort;
\ No newline at end of file
+pub mod execute_command;
+pub mod abort;
+pub mod build_status;
+pub mod retry_build;
+pub mod payload_files;
\ No newline at end of file
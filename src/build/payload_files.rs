@@ -0,0 +1,171 @@
+use std::{collections::HashMap, fs, io, os::unix::fs::DirBuilderExt, path::{Path, PathBuf}};
+
+use crate::{helpers::{template::{render_template, template_values, url_encode, Escape}, upload::{copy_file, UploadedFile}, utils::{create_file_with_dirs_and_content, param_values, secure_join_path}}, journal::build_journal::staged_uploads_dir, models::{app_state::{AppState, BuildRequest}, config::{Config, PayloadType}}};
+
+/// the project path of every `file` payload, by payload key
+/// checked when a build is queued and again when it starts, the project may have changed meanwhile
+pub fn payload_file_paths(config: &Config, payload: &HashMap<String, String>, build_id: &str, unique_id: &str) -> Result<Vec<(String, String)>, String> {
+    let values = template_values(
+        &param_values(&config.project.build.payload, payload),
+        build_id,
+        unique_id,
+        0,
+        &HashMap::new(),
+    );
+
+    let mut paths = Vec::new();
+    for file in config.project.build.payload.iter().filter(|payload| payload.r#type == PayloadType::File) {
+        let file_path = file.key2.as_deref().unwrap_or(file.key1.as_str());
+        let file_path = render_template(file_path, &values, Escape::Path)
+            .map_err(|e| format!("Invalid payload file path: {}", e))?;
+        let path = secure_join_path(&config.project.project_path, &file_path)
+            .ok_or_else(|| "Failed to create payload file: Path is not secure".to_string())?;
+        paths.push((file.key1.clone(), path));
+    }
+
+    Ok(paths)
+}
+
+/// the staged copy of the upload `key`
+fn staged_file(dir: &Path, key: &str) -> PathBuf {
+    dir.join(format!("{}.upload", url_encode(key)))
+}
+
+/// move the uploads of a build to its staging directory, where they wait until the build starts
+/// the directory is only created for builds with uploads and is readable by the owner only
+pub async fn stage_uploads(dir: &Path, files: &HashMap<String, UploadedFile>) -> io::Result<()> {
+    if files.is_empty() {
+        return Ok(());
+    }
+
+    fs::DirBuilder::new().recursive(true).mode(0o700).create(dir)?;
+    for (key, upload) in files {
+        upload.persist(&staged_file(dir, key)).await?;
+    }
+    Ok(())
+}
+
+/// hand the staged uploads of `from` to the queued build `to` it replaces, dropping the ones `to` had
+pub fn replace_staged_uploads(journal_path: &str, from: &str, to: &str) -> io::Result<()> {
+    let from = staged_uploads_dir(journal_path, from)?;
+    let to = staged_uploads_dir(journal_path, to)?;
+
+    match fs::remove_dir_all(&to) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+        _ => {}
+    }
+    if from.exists() {
+        fs::rename(from, to)?;
+    }
+    Ok(())
+}
+
+/// remove the staged uploads of a build, on the blocking pool
+pub async fn discard_staged_uploads(journal_path: &str, build_id: &str) {
+    let Ok(dir) = staged_uploads_dir(journal_path, build_id) else {
+        return;
+    };
+
+    let _ = tokio::task::spawn_blocking(move || match fs::remove_dir_all(&dir) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => {
+            println!("Failed to remove the staged uploads {}: {}", dir.display(), e);
+        }
+        _ => {}
+    }).await;
+}
+
+/// write the `file` payloads of a dequeued build into the project, uploads are copied from its staging directory
+/// a resumed build keeps the files already in the project
+pub async fn write_payload_files(config: &Config, build: &BuildRequest) -> Result<(), String> {
+    if build.reuse_workspace {
+        return Ok(());
+    }
+
+    let paths = payload_file_paths(config, &build.payload, &build.id, &build.unique_id)?;
+    let dir = staged_uploads_dir(&config.journal_path, &build.id)
+        .map_err(|e| format!("Failed to create payload file: {}", e))?;
+    let uploaded_files = build.uploaded_files.clone();
+    let payload = build.payload.clone();
+
+    tokio::task::spawn_blocking(move || {
+        for (key, path) in paths {
+            // uploads are copied byte for byte, text fields are written as is
+            let written = if uploaded_files.contains(&key) {
+                copy_file(&staged_file(&dir, &key), Path::new(&path))
+            } else if let Some(content) = payload.get(&key) {
+                create_file_with_dirs_and_content(&path, content)
+            } else {
+                // optional files that were not sent are left alone
+                continue;
+            };
+            written.map_err(|e| format!("Failed to create payload file {}: {}", key, e))?;
+        }
+        Ok(())
+    })
+    .await
+    .map_err(|e| format!("Failed to create payload file: {}", e))?
+}
+
+/// remove the staged uploads of a finished build, a failed one keeps them with the history so it can be retried
+pub async fn finish_staged_uploads(state: &AppState, build_id: &str, uploaded_files: &[String], is_failed: bool) {
+    let Ok(dir) = staged_uploads_dir(&state.config().journal_path, build_id) else {
+        return;
+    };
+
+    let history = state.history.clone();
+    let build_id = build_id.to_string();
+    let uploaded_files = uploaded_files.to_vec();
+    let _ = tokio::task::spawn_blocking(move || {
+        for key in uploaded_files.iter().filter(|_| is_failed) {
+            let path = staged_file(&dir, key);
+            if !path.is_file() {
+                continue;
+            }
+            if let Err(e) = history.keep_upload(&build_id, key, &path) {
+                println!("Failed to keep upload {} of build {}, it cannot be retried: {}", key, build_id, e);
+            }
+        }
+        let _ = fs::remove_dir_all(&dir);
+    }).await;
+}
+
+/// remove the uploads kept for a build that was retried or resumed, its new build has copies of them
+pub async fn drop_kept_uploads(state: &AppState, build_id: &str) {
+    let history = state.history.clone();
+    let build_id = build_id.to_string();
+    let _ = tokio::task::spawn_blocking(move || {
+        if let Err(e) = history.drop_uploads(&build_id) {
+            println!("Failed to remove the kept uploads of build {}: {}", build_id, e);
+        }
+    }).await;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn a_replacing_build_hands_over_its_uploads() {
+        let dir = std::env::temp_dir().join(format!("app_builder-staged-{}", crate::helpers::utils::generate_token(8)));
+        let journal_path = dir.join("journal.json").to_string_lossy().to_string();
+
+        let queued = staged_uploads_dir(&journal_path, "queued").unwrap();
+        fs::create_dir_all(&queued).unwrap();
+        fs::write(staged_file(&queued, "old"), "old").unwrap();
+
+        let replacing = staged_uploads_dir(&journal_path, "replacing").unwrap();
+        fs::create_dir_all(&replacing).unwrap();
+        fs::write(staged_file(&replacing, "new"), "new").unwrap();
+
+        replace_staged_uploads(&journal_path, "replacing", "queued").unwrap();
+        assert!(!replacing.exists());
+        assert!(!staged_file(&queued, "old").exists());
+        assert_eq!(fs::read_to_string(staged_file(&queued, "new")).unwrap(), "new");
+
+        // a replacing build without uploads leaves none behind
+        replace_staged_uploads(&journal_path, "replacing", "queued").unwrap();
+        assert!(!queued.exists());
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
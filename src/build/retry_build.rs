@@ -0,0 +1,272 @@
+use std::collections::HashMap;
+
+use actix_web::{web, HttpRequest, HttpResponse, Responder};
+use serde::{de::DeserializeOwned, Deserialize};
+
+use super::{build_init::{enqueue_build, BuildOrigin}, build_status::unauthorized, payload_files::drop_kept_uploads};
+use crate::{auth::check_auth::{is_authorized, request_token}, helpers::{payload_schema::validate_payload, upload::UploadedFile}, models::{app_state::{AppState, BuildProcess, BuildResponse, ValidationResponse}, status::Status}};
+
+#[derive(Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+pub struct RetryRequest {
+    /// skip the steps before the one that failed
+    #[serde(default)]
+    pub from_failed_step: bool,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+pub struct ResumeRequest {
+    /// first step to run again, the failed one when missing
+    #[serde(default)]
+    pub step: Option<usize>,
+}
+
+/// the json body of a retry or resume, only an empty body stands for the defaults
+fn request_body<T: DeserializeOwned + Default>(body: &[u8], build_id: &str) -> Result<T, HttpResponse> {
+    if body.iter().all(u8::is_ascii_whitespace) {
+        return Ok(T::default());
+    }
+    serde_json::from_slice(body).map_err(|e| {
+        let res = BuildResponse{
+            message: format!("Invalid json body: {}", e),
+            status: Status::InvalidPayload,
+            build_id: Some(build_id.to_string()),
+            token: None
+        };
+        HttpResponse::BadRequest().json(res)
+    })
+}
+
+fn conflict(build_id: String, status: Status, message: String) -> HttpResponse {
+    let res = BuildResponse{
+        message,
+        status,
+        build_id: Some(build_id),
+        token: None
+    };
+    HttpResponse::Conflict().json(res)
+}
+
+/// queue a finished build again with its stored payload, `file` payloads are written again,
+/// uploads from the copies kept with the history for failed builds; the new build links back through `retry_of`
+/// and the copies move on to it, a build is retried once unless its retry fails as well
+/// with `from_failed_step` the values extracted before the failed step are restored
+pub async fn retry_build(
+    req: HttpRequest,
+    path: web::Path<String>,
+    body: web::Bytes,
+    state: web::Data<AppState>,
+) -> impl Responder {
+
+    if !is_authorized(&req, state.clone()).await {
+        return unauthorized();
+    }
+
+    let build_id = path.into_inner();
+    let retry: RetryRequest = match request_body(&body, &build_id) {
+        Ok(retry) => retry,
+        Err(res) => return res,
+    };
+
+    let build = match finished_build(&state, build_id.clone()).await {
+        Ok(build) => build,
+        Err(res) => return res,
+    };
+
+    let start_step = if retry.from_failed_step {
+        if build.status == Status::Success {
+            return conflict(build_id, Status::Success, "Build succeeded, there is no failed step".to_string());
+        }
+        build.current_step.max(1)
+    } else {
+        1
+    };
+
+    let files = match kept_files(&state, &build_id, &build.uploaded_files, true).await {
+        Ok(files) => files,
+        Err(res) => return res,
+    };
+
+    let step_outputs = build.step_outputs.range(..start_step)
+        .map(|(step, outputs)| (*step, outputs.clone()))
+        .collect();
+
+    let payload = match sent_payload(&state, build.payload) {
+        Ok(payload) => payload,
+        Err(res) => return res,
+    };
+
+    let origin = BuildOrigin{
+        token: request_token(&req),
+        priority: build.priority,
+        retry_of: Some(build_id.clone()),
+        start_step,
+        step_outputs,
+        reuse_workspace: false,
+        kept_uploads: Vec::new(),
+    };
+
+    queue_again(&state, &build_id, payload, files, origin).await
+}
+
+/// queue a failed build again from step N in the same workspace, N defaults to the step that failed
+/// the `file` payloads are not written again and the values extracted before step N are restored
+pub async fn resume_build(
+    req: HttpRequest,
+    path: web::Path<String>,
+    body: web::Bytes,
+    state: web::Data<AppState>,
+) -> impl Responder {
+
+    if !is_authorized(&req, state.clone()).await {
+        return unauthorized();
+    }
+
+    let build_id = path.into_inner();
+    let resume: ResumeRequest = match request_body(&body, &build_id) {
+        Ok(resume) => resume,
+        Err(res) => return res,
+    };
+
+    let build = match finished_build(&state, build_id.clone()).await {
+        Ok(build) => build,
+        Err(res) => return res,
+    };
+
+    if build.status == Status::Success {
+        return conflict(build_id, Status::Success, "Build succeeded, there is nothing to resume".to_string());
+    }
+
+    // steps after the failed one never ran, so their inputs were never extracted
+    let failed_step = build.current_step.max(1);
+    let start_step = resume.step.unwrap_or(failed_step);
+    if start_step < 1 || start_step > failed_step {
+        let res = BuildResponse{
+            message: format!("Step must be between 1 and {}", failed_step),
+            status: Status::InvalidPayload,
+            build_id: Some(build_id),
+            token: None
+        };
+        return HttpResponse::BadRequest().json(res);
+    }
+
+    // the files are already in the workspace, the copies are only kept in case the resumed build fails too
+    let files = match kept_files(&state, &build_id, &build.uploaded_files, false).await {
+        Ok(files) => files,
+        Err(res) => return res,
+    };
+
+    let step_outputs = build.step_outputs.range(..start_step)
+        .map(|(step, outputs)| (*step, outputs.clone()))
+        .collect();
+
+    let payload = match sent_payload(&state, build.payload) {
+        Ok(payload) => payload,
+        Err(res) => return res,
+    };
+
+    let origin = BuildOrigin{
+        token: request_token(&req),
+        priority: build.priority,
+        retry_of: Some(build_id.clone()),
+        start_step,
+        step_outputs,
+        reuse_workspace: true,
+        kept_uploads: build.uploaded_files,
+    };
+
+    queue_again(&state, &build_id, payload, files, origin).await
+}
+
+/// temporary copies of the uploads kept for a build, staged for its new build like fresh uploads
+/// a missing copy is a conflict when the files have to be written again, else it is skipped
+async fn kept_files(state: &AppState, build_id: &str, uploaded_files: &[String], is_required: bool) -> Result<HashMap<String, UploadedFile>, HttpResponse> {
+    let mut files = HashMap::new();
+    for key in uploaded_files {
+        let upload = match state.history.kept_upload(build_id, key) {
+            Ok(Some(path)) => UploadedFile::copy_of(&path).await,
+            Ok(None) if is_required => return Err(conflict(build_id.to_string(), Status::FileCreateFailed, format!("File {} was uploaded and is not kept, queue a new build instead", key))),
+            Ok(None) => continue,
+            Err(e) => Err(e),
+        };
+        match upload {
+            Ok(upload) => {
+                files.insert(key.clone(), upload);
+            }
+            Err(e) => {
+                println!("Failed to read the kept upload {} of build {}: {}", key, build_id, e);
+                return Err(conflict(build_id.to_string(), Status::FileCreateFailed, format!("Failed to read the kept upload {}", key)));
+            }
+        }
+    }
+    Ok(files)
+}
+
+/// queue a finished build again, its kept uploads are dropped once the new build is queued
+async fn queue_again(state: &web::Data<AppState>, build_id: &str, payload: HashMap<String, String>, files: HashMap<String, UploadedFile>, origin: BuildOrigin) -> HttpResponse {
+    let res = enqueue_build(state, payload, files, origin).await;
+    if res.status().is_success() {
+        drop_kept_uploads(state, build_id).await;
+    }
+    res
+}
+
+/// a build that is neither running nor queued, from the history
+async fn finished_build(state: &AppState, build_id: String) -> Result<BuildProcess, HttpResponse> {
+    if state.builds.current_builds.lock().await.contains_key(&build_id) {
+        return Err(conflict(build_id, Status::AlreadyBuilding, "Build is still running".to_string()));
+    }
+    if state.builds.build_queue.lock().await.iter().any(|build| build.id == build_id) {
+        return Err(conflict(build_id, Status::AlreadyQueue, "Build is still queued".to_string()));
+    }
+
+    match state.history.get(&build_id) {
+        Ok(Some(build)) => Ok(build),
+        Ok(None) => {
+            let res = BuildResponse{
+                message: format!("Build not found: {}", build_id),
+                status: Status::NotFound,
+                build_id: Some(build_id),
+                token: None
+            };
+            Err(HttpResponse::NotFound().json(res))
+        }
+        Err(e) => {
+            println!("Failed to read the build history: {}", e);
+            let res = BuildResponse{
+                message: "Failed to read the build history".to_string(),
+                status: Status::SomethingWentWrong,
+                build_id: Some(build_id),
+                token: None
+            };
+            Err(HttpResponse::InternalServerError().json(res))
+        }
+    }
+}
+
+/// the part of a stored payload that was sent, validated against the current config
+fn sent_payload(state: &AppState, payload: HashMap<String, String>) -> Result<HashMap<String, String>, HttpResponse> {
+    // the values extracted by the steps live in the same map
+    let config = state.config();
+    let build_config = &config.project.build;
+    let mut payload: HashMap<String, String> = payload.into_iter()
+        .filter(|(key, _)| {
+            key == &build_config.unique_build_key
+                || key == "project_token"
+                || build_config.payload.iter().any(|payload| &payload.key1 == key)
+        })
+        .collect();
+
+    // the config may have changed since
+    if let Err(errors) = validate_payload(&build_config.payload, &mut payload) {
+        let res = ValidationResponse{
+            message: "Invalid payload".to_string(),
+            status: Status::InvalidPayload,
+            errors,
+        };
+        return Err(HttpResponse::UnprocessableEntity().json(res));
+    }
+
+    Ok(payload)
+}
@@ -1,40 +1,101 @@
-use std::{collections::HashMap, process::Stdio};
+use std::{collections::HashMap, time::Duration};
 
 use actix_web::web;
-use chrono::format;
-use tokio::{ process::Command};
+use tokio::time::Instant;
 
-use crate::{helpers::utils::{extract_payload, read_stderr, read_stdout, replace_placeholders}, models::{app_state::{ AppState, BuildLog, ChannelMessage, ProjectLog}, config::{CommandConfig}, status::Status}};
+use super::execute_command::{execute_command, CommandOutcome};
+
+use crate::{helpers::{template::{render_command, template_values}, utils::{extract_payload, push_build_log, OutputTarget}}, models::{app_state::{ AppState, BuildHandle, BuildLog, ChannelMessage, ProjectLog}, cancel_token::CancelReason, config::{CommandConfig, NoOutputAction}, status::Status}};
+
+/// set the status of a running build
+async fn set_build_status(state: &web::Data<AppState>, build_id: &str, status: Status) {
+    let mut current_builds = state.builds.current_builds.lock().await;
+    if let Some(current_build) = current_builds.get_mut(build_id) {
+        current_build.status = status;
+    }
+}
+
+/// the deadline of a command, `timeout_secs` from now
+fn command_deadline(command: &CommandConfig) -> Option<Instant> {
+    command.timeout_secs.map(|secs| Instant::now() + Duration::from_secs(secs))
+}
+
+/// how long a command may stay silent
+fn no_output_timeout(command: &CommandConfig) -> Option<Duration> {
+    command.no_output_timeout.map(Duration::from_secs)
+}
+
+/// whether a failed attempt is worth another try
+/// timeouts and silence have no exit code, they are retried unless the codes are restricted
+fn should_retry(command: &CommandConfig, outcome: &CommandOutcome) -> bool {
+    match outcome {
+        CommandOutcome::Exited(status) if !status.success() => match &command.retry_on_exit_codes {
+            Some(codes) => status.code().is_some_and(|code| codes.contains(&code)),
+            None => true,
+        },
+        CommandOutcome::TimedOut | CommandOutcome::NoOutput => command.retry_on_exit_codes.is_none(),
+        _ => false,
+    }
+}
 
 /// execute commands and handle the output
-pub async fn run_build(state: web::Data<AppState>) {
+pub async fn run_build(state: web::Data<AppState>, build_id: String, handle: BuildHandle) {
 
     let mut env_map: HashMap<String, String> = HashMap::new();
     let mut param_map: HashMap<String, String> = HashMap::new();
 
-    extract_payload(&state, &mut env_map, &mut param_map).await;
+    // the config the build started with, even when it is reloaded meanwhile
+    let config = handle.config.clone();
+
+    extract_payload(&state, &config, &build_id, &mut env_map, &mut param_map).await;
+
+    // the values a resumed build restored from the steps it skips
+    if let Some(build) = state.builds.current_builds.lock().await.get(&build_id) {
+        env_map.extend(build.outputs.clone());
+    }
 
+    // the build timeout cancels whatever runs at that moment, the hooks are not affected
+    let build_timer = config.project.build.timeout_secs.map(|secs| {
+        let cancel = handle.cancel.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_secs(secs)).await;
+            cancel.cancel(CancelReason::TimedOut);
+        })
+    });
 
 
+    let start_step = {
+        let current_builds = state.builds.current_builds.lock().await;
+        current_builds.get(&build_id).map(|build| build.start_step).unwrap_or(1)
+    };
+
     let mut step = 1;
-    for command in &state.config.project.build.commands {
+    for command in &config.project.build.commands {
 
+        // retried or resumed from a later step
+        if step < start_step {
+            step += 1;
+            continue;
+        }
 
-        {
+        let values = {
             
             let log = BuildLog {
                 timestamp: chrono::Utc::now(),
                 status: Status::StartingCommand,
                 step,
+                attempt: 1,
                 message: format!("Running command: {}", command.title),
              };
             if command.send_to_sock {
                     let json_str = serde_json::to_string(&log).unwrap();
-                    let _ = state.build_sender.send(ChannelMessage::Data(json_str));
+                    let _ = handle.sender.send(ChannelMessage::Data(json_str));
             }
 
-            let mut  current_build_guard = state.builds.current_build.lock().await;
-            let  current_build = current_build_guard.as_mut().unwrap();
+            let mut current_builds = state.builds.current_builds.lock().await;
+            let Some(current_build) = current_builds.get_mut(&build_id) else {
+                return;
+            };
             current_build.current_step = step;
 
             current_build.logs.push(log.clone());
@@ -43,116 +104,183 @@ pub async fn run_build(state: web::Data<AppState>) {
                 id: current_build.id.clone(),
                 unique_id: current_build.unique_id.clone(),
                 socket_token: current_build.socket_token.clone(),
-                step: step,
+                step,
                 state: Status::StartingCommand,
                 timestamp: chrono::Utc::now(),
 
                 message: command.title.clone()
             };
-            drop(current_build_guard);
+            let values = template_values(&param_map, &build_id, &current_build.unique_id, step, &current_build.outputs);
+            drop(current_builds);
 
             let project_log_json = serde_json::to_string(&project_log).unwrap();
             let _ = state.project_sender.send(ChannelMessage::Data(project_log_json));
 
             let mut project_logs = state.project_logs.lock().await;
             project_logs.push(project_log);
-        }
+            values
+        };
 
         
 
-        let  command_with_params = replace_placeholders(&command.command, &param_map);
+        let command_with_params = match render_command(&command.command, &values) {
+            Ok(command_with_params) => command_with_params,
+            Err(e) => {
+                let target = OutputTarget {
+                    state: &state,
+                    build_id: &build_id,
+                    handle: &handle,
+                    step,
+                    attempt: 1,
+                    send_to_sock: command.send_to_sock,
+                    bypass_termination: false,
+                };
+                push_build_log(&target, Status::Error, format!("Invalid command template: {}", e)).await;
+                set_build_status(&state, &build_id, Status::Error).await;
+
+                if command.abort_on_error {
+                    break;
+                }
+                step += 1;
+                continue;
+            }
+        };
 
-        let command_with_env = format!("{} && echo '+_+_+_\n' && env", command_with_params);
-       
-       println!("Running command: {}", command_with_env);
-        let  child = Command::new("bash")
-            .arg("-c")
-            .envs(&env_map)
-            .current_dir(state.config.project.project_path.as_str())
-            .arg( &command_with_env )
-            
-            .stdout(Stdio::piped())
-            .stderr(Stdio::piped())
-            .spawn()
-            ;
+       println!("Running command: {}", command_with_params.command);
 
-         
-        
-        let mut child = child.unwrap();
+        let mut attempt = 1;
+        let outcome = loop {
+            let target = OutputTarget {
+                state: &state,
+                build_id: &build_id,
+                handle: &handle,
+                step,
+                attempt,
+                send_to_sock: command.send_to_sock,
+                bypass_termination: false,
+            };
 
-        let  stdout = child.stdout.take().unwrap();
-        let  stderr = child.stderr.take().unwrap();
+            if attempt > 1 {
+                push_build_log(&target, Status::StartingCommand, format!("Retrying command: {} (attempt {}/{})", command.title, attempt, command.retries + 1)).await;
+            }
 
-       
-        
-        tokio::join!(
-            read_stdout(stdout, step, &state,command.send_to_sock,false,&command.extract_envs,&mut env_map ),
-            read_stderr(stderr, step, &state,command.send_to_sock,false)
-        );
-        
-        
+            let outcome = execute_command(&target, &command_with_params, &mut env_map, &command.extract_envs, command_deadline(command), no_output_timeout(command)).await;
+
+            {
+                let mut current_builds = state.builds.current_builds.lock().await;
+                if let Some(current_build) = current_builds.get_mut(&build_id) {
+                    current_build.attempts.insert(step, attempt);
+                }
+            }
+
+            if attempt > command.retries || !should_retry(command, &outcome) || handle.cancel.is_cancelled() {
+                break outcome;
+            }
+
+            push_build_log(&target, Status::Warning, format!("Attempt {} failed, retrying in {}s", attempt, command.retry_delay_secs)).await;
+
+            tokio::select! {
+                _ = tokio::time::sleep(Duration::from_secs(command.retry_delay_secs)) => {}
+                _ = handle.cancel.cancelled() => {}
+            }
+
+            // cancelled while waiting, the next attempt must not start
+            if handle.cancel.is_cancelled() {
+                break outcome;
+            }
 
-        let status = child.wait().await.expect("Failed to wait on child");
-        if status.success() {
-            let mut  current_build = state.builds.current_build.lock().await;
-            let  current_build = current_build.as_mut().unwrap();
-            current_build.status = Status::Success; //nothing much to do
+            attempt += 1;
+        };
+
+        let target = OutputTarget {
+            state: &state,
+            build_id: &build_id,
+            handle: &handle,
+            step,
+            attempt,
+            send_to_sock: command.send_to_sock,
+            bypass_termination: false,
+        };
+
+        if let CommandOutcome::SpawnFailed(e) = &outcome {
+            push_build_log(&target, Status::Error, format!("Failed to run command: {}", e)).await;
+        }
+
+        let is_silence_abort = matches!(outcome, CommandOutcome::NoOutput) && command.no_output_action == NoOutputAction::Abort;
+
+        if handle.cancel.reason() == Some(CancelReason::TimedOut) {
+            push_build_log(&target, Status::TimedOut, format!("Build timed out after {}s", config.project.build.timeout_secs.unwrap_or_default())).await;
+            set_build_status(&state, &build_id, Status::TimedOut).await;
+            break;
+        }
+
+        if matches!(outcome, CommandOutcome::Aborted) || is_silence_abort || handle.cancel.is_cancelled() {
+            let message = if handle.cancel.reason() == Some(CancelReason::Shutdown) {"Build aborted, the server is shutting down"} else {"Build aborted"};
+            push_build_log(&target, Status::Aborted, message.to_string()).await;
+            set_build_status(&state, &build_id, Status::Aborted).await;
+            break;
+        }
+
+        if matches!(outcome, CommandOutcome::TimedOut) {
+            push_build_log(&target, Status::TimedOut, format!("Command timed out after {}s", command.timeout_secs.unwrap_or_default())).await;
+            set_build_status(&state, &build_id, Status::TimedOut).await;
+
+            if command.abort_on_error {
+                break;
+            }
+        } else if outcome.is_success() {
+            set_build_status(&state, &build_id, Status::Success).await; //nothing much to do
             
         } else {
 
-            let mut  current_build = state.builds.current_build.lock().await;
-            let  current_build = current_build.as_mut().unwrap();
-            current_build.status = Status::Error; //
+            set_build_status(&state, &build_id, Status::Error).await;
             if command.abort_on_error {
-                // *state.is_terminated.lock().await = true;
                 break;
             }//handle the case here all the other will also be terminated, handle here
         }
 
-        if * state.is_terminated.lock().await {
-            child.kill().await.unwrap();
-            let mut  current_build = state.builds.current_build.lock().await;
-            let  current_build = current_build.as_mut().unwrap();
-            current_build.status = Status::Aborted;
-            break;
-        }
-
         step += 1;
 
 
     }//loop each command
 
+    if let Some(build_timer) = build_timer {
+        build_timer.abort();
+    }
     
-        let mut  current_build_guard = state.builds.current_build.lock().await;
-        let  current_build = current_build_guard.as_mut().unwrap();
-        let commands = if current_build.status == Status::Success{
+        let current_builds = state.builds.current_builds.lock().await;
+        let is_success = current_builds.get(&build_id).is_some_and(|build| build.status == Status::Success);
+        drop(current_builds);
+
+        let commands = if is_success{
 
-            &state.config.project.build.run_on_success
+            &config.project.build.run_on_success
         }
         else{
-            &state.config.project.build.run_on_failure
+            &config.project.build.run_on_failure
         };
 
-        drop(current_build_guard);   
-
         
        
-        run_on_success_error_payload(&state, &mut env_map, &mut param_map,&commands, step).await;
+        run_on_success_error_payload(&state, &build_id, &handle, &mut env_map, &param_map, commands, step).await;
         {
 
-            let mut  current_build_guard = state.builds.current_build.lock().await;
-            let  current_build = current_build_guard.as_mut().unwrap();
+            let current_builds = state.builds.current_builds.lock().await;
+            let Some(current_build) = current_builds.get(&build_id) else {
+                return;
+            };
             
             let project_log = ProjectLog{
                 id: current_build.id.clone(),
                 unique_id: current_build.unique_id.clone(),
                 socket_token: current_build.socket_token.clone(),
-                step: step,
+                step,
                 timestamp: chrono::Utc::now(),
 
                 state: current_build.status.clone(),
                 message: "Finalizing build".to_string()
             };
+            drop(current_builds);
 
             let project_log_json = serde_json::to_string(&project_log).unwrap();
             let _ = state.project_sender.send(ChannelMessage::Data(project_log_json));
@@ -165,53 +293,45 @@ pub async fn run_build(state: web::Data<AppState>) {
 }
 
 
-pub async fn run_on_success_error_payload(state: &web::Data<AppState>,env_map:&mut HashMap<String,String>,param_map:&mut HashMap<String,String>,commands:&Vec<CommandConfig>,step: usize) {
+pub async fn run_on_success_error_payload(state: &web::Data<AppState>,build_id: &str,handle: &BuildHandle,env_map:&mut HashMap<String,String>,param_map:&HashMap<String,String>,commands:&[CommandConfig],step: usize) {
 
     println!("Running on success error payload");
-    let mut step = step;
-    for command in commands {
-
-        let  command_with_params = replace_placeholders(&command.command, &param_map);
-
-        let command_with_env = format!("{} && echo '+_+_+_\n' && env", command_with_params);
-        
-        
-        let  child = Command::new("bash")
-            .arg("-c")
-            .envs(&*env_map)
-            .arg( &command_with_env )
-            .current_dir(state.config.project.project_path.as_str())
-            .stdout(Stdio::piped())
-            .stderr(Stdio::piped())
-            .spawn()
-            ;
-
-           
-            
-        let mut child = child.unwrap();
-        
-
-        let  stdout: tokio::process::ChildStdout = child.stdout.take().unwrap();
-        let  stderr = child.stderr.take().unwrap();
-
-       
-        
-        tokio::join!(
-            read_stdout(stdout, step, &state,command.send_to_sock,true,&command.extract_envs, env_map ),
-            read_stderr(stderr, step, &state,command.send_to_sock,true)
-        );
-        
-    
+    for (index, command) in commands.iter().enumerate() {
+        let step = step + index;
+
+        let target = OutputTarget {
+            state,
+            build_id,
+            handle,
+            step,
+            attempt: 1,
+            send_to_sock: command.send_to_sock,
+            bypass_termination: true,
+        };
 
-        let status = child.wait().await.expect("Failed to wait on child");
-        if !status.success() {
-            if command.abort_on_error {
-                break;
-            }//handle the case here all the other will also be terminated, handle here
-        }
+        let values = {
+            let current_builds = state.builds.current_builds.lock().await;
+            match current_builds.get(build_id) {
+                Some(build) => template_values(param_map, build_id, &build.unique_id, step, &build.outputs),
+                None => return,
+            }
+        };
 
+        let command_with_params = match render_command(&command.command, &values) {
+            Ok(command_with_params) => command_with_params,
+            Err(e) => {
+                push_build_log(&target, Status::Error, format!("Invalid command template: {}", e)).await;
+                if command.abort_on_error {
+                    break;
+                }
+                continue;
+            }
+        };
 
-        step += 1;
+        let outcome = execute_command(&target, &command_with_params, env_map, &command.extract_envs, command_deadline(command), no_output_timeout(command)).await;
+        if !outcome.is_success() && command.abort_on_error {
+            break;
+        }//handle the case here all the other will also be terminated, handle here
 
 
     }//loop each command
@@ -219,4 +339,4 @@ pub async fn run_on_success_error_payload(state: &web::Data<AppState>,env_map:&m
 
 
 
-}
\ No newline at end of file
+}
@@ -0,0 +1 @@
+pub mod watch_config;
\ No newline at end of file
@@ -0,0 +1,120 @@
+use std::{fs, time::{Duration, SystemTime}};
+
+use actix_web::web;
+use anyhow::{bail, Result};
+use serde_json::json;
+use tokio::signal::unix::{signal, SignalKind};
+
+use crate::{helpers::utils::is_path_exits, models::{app_state::{AppState, ChannelMessage}, config::Config}};
+
+/// load and check the config file the way the startup does
+fn load_config(path: &str) -> Result<Config> {
+    let config = Config::load(path)?;
+    if !is_path_exits(&config.project.project_path) {
+        bail!("Project path does not exist {}", config.project.project_path);
+    }
+    Ok(config)
+}
+
+/// settings that are only read on startup, they keep their old value until a restart
+/// the certificate watcher keeps the startup `ssl` section and the journal stays where the queue was saved
+/// returns the names of the ones that changed
+fn keep_restart_only(current: &Config, new: &mut Config) -> Vec<&'static str> {
+    let mut changes = Vec::new();
+    if current.port != new.port {
+        changes.push("port");
+    }
+    if current.ssl.enable_ssl != new.ssl.enable_ssl {
+        changes.push("ssl.enable_ssl");
+    }
+    if current.ssl.certificate_path != new.ssl.certificate_path {
+        changes.push("ssl.certificate_path");
+    }
+    if current.ssl.certificate_key_path != new.ssl.certificate_key_path {
+        changes.push("ssl.certificate_key_path");
+    }
+    if current.ssl.reload_interval != new.ssl.reload_interval {
+        changes.push("ssl.reload_interval");
+    }
+    if current.history_path != new.history_path {
+        changes.push("history_path");
+    }
+    if current.journal_path != new.journal_path {
+        changes.push("journal_path");
+    }
+
+    new.port = current.port;
+    new.ssl = current.ssl.clone();
+    new.history_path = current.history_path.clone();
+    new.journal_path = current.journal_path.clone();
+    changes
+}
+
+/// swap in the config at `path` for everything that starts from now on, running builds keep theirs
+/// a config that fails to load or validate keeps the current one, the outcome goes to the project socket
+pub fn reload_config(state: &AppState, path: &str) -> Result<()> {
+    let result = load_config(path).map(|mut config| {
+        let changes = keep_restart_only(&state.config(), &mut config);
+        if !changes.is_empty() {
+            println!("Config changes of {} apply after a restart", changes.join(", "));
+        }
+        state.set_config(config);
+    });
+
+    let event = match &result {
+        Ok(()) => {
+            println!("Reloaded config {}", path);
+            json!({ "event": "config", "status": "reloaded", "message": format!("Reloaded config {}", path) })
+        }
+        Err(e) => {
+            println!("Failed to reload config {}, keeping the current one: {:#}", path, e);
+            json!({ "event": "config", "status": "error", "message": format!("Failed to reload config: {:#}", e) })
+        }
+    };
+    let _ = state.project_sender.send(ChannelMessage::Data(event.to_string()));
+
+    result
+}
+
+fn config_modified(path: &str) -> Option<SystemTime> {
+    fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+/// reload the config on SIGHUP and whenever the file changes, polled every `config_reload_interval`
+pub async fn watch_config(state: web::Data<AppState>, path: String) {
+    let mut hangup = match signal(SignalKind::hangup()) {
+        Ok(hangup) => hangup,
+        Err(e) => {
+            println!("Failed to listen for SIGHUP, the config is not reloaded: {}", e);
+            return;
+        }
+    };
+
+    let mut last_modified = config_modified(&path);
+
+    loop {
+        let interval = state.config().config_reload_interval;
+        let poll = async {
+            if interval == 0 {
+                return std::future::pending().await;
+            }
+            tokio::time::sleep(Duration::from_secs(interval)).await;
+        };
+
+        tokio::select! {
+            _ = hangup.recv() => {
+                println!("Received SIGHUP, reloading config {}", path);
+            }
+            _ = poll => {
+                let modified = config_modified(&path);
+                if modified.is_none() || modified == last_modified {
+                    continue;
+                }
+            }
+        }
+
+        // a broken file is reported once, the next change tries again
+        last_modified = config_modified(&path);
+        let _ = reload_config(&state, &path);
+    }
+}
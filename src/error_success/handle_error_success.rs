@@ -3,61 +3,66 @@ use std::{fs, path::{ Path}, time::Duration};
 use actix_web::web;
 use tokio::time::sleep;
 
-use crate::{ helpers::utils::{save_log, secure_join_path, send_to_other_server}, models::{app_state::{AppState, BuildProcess}, config::PayloadType}};
+use crate::{ helpers::{template::{render_template, template_values, Escape}, utils::{param_values, save_log, secure_join_path, send_to_other_server}}, models::{app_state::{AppState, BuildProcess}, config::{Config, PayloadType}}};
 
 
 
 /// handle the success,error log to be send to the other server of the build
-pub async fn handle_error_success(state: web::Data<AppState>,current_build: BuildProcess) {
+/// returns the build with its `out_payload` filled in, `config` is the one the build ran with
+pub async fn handle_error_success(state: web::Data<AppState>,config: &Config,current_build: BuildProcess) -> BuildProcess {
 
 
     let log_str = serde_json::to_string(&current_build).unwrap();
 
     let mut buld = current_build.clone();
 
-        if state.config.enable_logs{
-            save_log(&state.config.log_path, log_str.clone(), current_build.unique_id.clone()).await;
+        if config.enable_logs{
+            save_log(&config.log_path, log_str.clone(), current_build.unique_id.clone()).await;
         }
         
 
-        let url = state.config.project.build.on_success_failure.clone();
+        let values = template_values(
+            &param_values(&config.project.build.payload, &current_build.payload),
+            &current_build.id,
+            &current_build.unique_id,
+            current_build.current_step,
+            &current_build.outputs,
+        );
+
         let state_clone = state.clone();
 
-        for out_paylaod in state.config.project.build.on_success_error_payload.clone(){
+        for out_paylaod in config.project.build.on_success_error_payload.clone(){
             
             if out_paylaod.r#type == PayloadType::File{
 
-                let file_path = if out_paylaod.key2.is_some() {
-                    out_paylaod.key2.as_ref().unwrap()
-                } else {
-                    out_paylaod.key1.as_str()
+                let file_path = out_paylaod.key2.as_deref().unwrap_or(out_paylaod.key1.as_str());
+                let file_path = match render_template(file_path, &values, Escape::Path) {
+                    Ok(file_path) => file_path,
+                    Err(e) => {
+                        println!("Failed to read payload file: {}", e);
+                        continue;
+                    }
                 };
-                let path_relative = secure_join_path(&state.config.project.project_path, &file_path);
+                let path_relative = secure_join_path(&config.project.project_path, &file_path);
                 if path_relative.is_none(){
                     println!("Failed to create payload file: Path is not secure");
                     continue;
                 }
                 let path_relative = path_relative.unwrap();
-                // let path_relative = format!("{}/{}", state.config.project.project_path, file_path);
+                // let path_relative = format!("{}/{}", config.project.project_path, file_path);
                 // println!("path_relative {}", path_relative);
                 let path = Path::new(path_relative.as_str());
 
-                if path.exists() {
-                    let string_opt =  fs::read_to_string(path).ok();
-                    if string_opt.is_some() {
-                        let string = string_opt.unwrap();
-                        buld.out_payload.insert(out_paylaod.key1.to_string(), string);
+                if path.exists()
+                    && let Ok(string) = fs::read_to_string(path)
+                {
+                    buld.out_payload.insert(out_paylaod.key1.to_string(), string);
                 }
-            }
                 continue;
 
             }//handle file reading here
 
-            let env_name = if out_paylaod.key2.is_some() {
-                out_paylaod.key2.as_ref().unwrap()
-            } else {
-                out_paylaod.key1.as_str()
-            };
+            let env_name = out_paylaod.key2.as_deref().unwrap_or(out_paylaod.key1.as_str());
 
             let env_value = current_build.payload.get(env_name);
 
@@ -70,6 +75,19 @@ pub async fn handle_error_success(state: web::Data<AppState>,current_build: Buil
         }
     
         // println!("out_payload {:?}", buld.out_payload);
+        let url = match render_template(&config.project.build.on_success_failure, &values, Escape::Url) {
+            Ok(url) => url,
+            Err(e) => {
+                println!("Invalid callback url: {}", e);
+                let mut error_logs = state.builds.failed_history.lock().await;
+                error_logs.push(buld.clone());
+                return buld;
+            }
+        };
+
+        let log_str = serde_json::to_string(&buld).unwrap();
+        let finished_build = buld.clone();
+
         let _ = tokio::spawn(async move {
             let is_send = send_to_other_server(url.clone(), log_str.clone()).await;
             
@@ -88,5 +106,5 @@ pub async fn handle_error_success(state: web::Data<AppState>,current_build: Buil
 
         }).await; //wait here until the thread is done
 
-
+        finished_build
 }
@@ -1 +1,5 @@
-pub mod utils;
\ No newline at end of file
+pub mod utils;
+pub mod template;
+pub mod payload_schema;
+pub mod upload;
+pub mod redact;
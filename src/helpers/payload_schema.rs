@@ -0,0 +1,195 @@
+use std::collections::HashMap;
+
+use regex::Regex;
+use serde::Serialize;
+
+use crate::models::config::{Payload, ValueType};
+
+/// why a single payload value was rejected
+#[derive(Debug, Clone, Serialize)]
+pub struct PayloadError {
+    pub key: String,
+    pub message: String,
+}
+
+/// check one value against its schema, bools are normalized to `true`/`false`
+fn validate_value(schema: &Payload, value: &str) -> Result<String, String> {
+    let value = match schema.value_type {
+        ValueType::String => value.to_string(),
+        ValueType::Int => {
+            value.trim().parse::<i64>().map_err(|_| format!("'{}' is not an integer", value))?;
+            value.trim().to_string()
+        }
+        ValueType::Bool => match value.trim().to_lowercase().as_str() {
+            "true" | "1" => "true".to_string(),
+            "false" | "0" => "false".to_string(),
+            _ => return Err(format!("'{}' is not a boolean", value)),
+        },
+        ValueType::Enum => {
+            if !schema.values.iter().any(|allowed| allowed == value) {
+                return Err(format!("'{}' is not one of {}", value, schema.values.join(", ")));
+            }
+            value.to_string()
+        }
+    };
+
+    if let Some(pattern) = &schema.pattern {
+        // anchored, the pattern has to match the whole value
+        let regex = Regex::new(&format!("^(?:{})$", pattern)).map_err(|e| format!("Invalid pattern: {}", e))?;
+        if !regex.is_match(&value) {
+            return Err(format!("'{}' does not match {}", value, pattern));
+        }
+    }
+
+    Ok(value)
+}
+
+/// validate the request payload against the configured schema
+/// defaults are filled in and bools normalized, all errors are returned together
+pub fn validate_payload(schema: &[Payload], payload: &mut HashMap<String, String>) -> Result<(), Vec<PayloadError>> {
+    let mut errors = Vec::new();
+
+    for field in schema {
+        let value = match payload.get(&field.key1).or(field.default.as_ref()) {
+            Some(value) => value.clone(),
+            None => {
+                if field.required {
+                    errors.push(PayloadError {
+                        key: field.key1.clone(),
+                        message: "Missing required value".to_string(),
+                    });
+                }
+                continue;
+            }
+        };
+
+        match validate_value(field, &value) {
+            Ok(value) => {
+                payload.insert(field.key1.clone(), value);
+            }
+            Err(message) => errors.push(PayloadError { key: field.key1.clone(), message }),
+        }
+    }
+
+    if errors.is_empty() { Ok(()) } else { Err(errors) }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(serde::Deserialize)]
+    struct Schema {
+        payload: Vec<Payload>,
+    }
+
+    fn schema(toml: &str) -> Vec<Payload> {
+        toml::from_str::<Schema>(toml).unwrap().payload
+    }
+
+    fn payload(pairs: &[(&str, &str)]) -> HashMap<String, String> {
+        pairs.iter().map(|(key, value)| (key.to_string(), value.to_string())).collect()
+    }
+
+    #[test]
+    fn fills_in_defaults() {
+        let schema = schema(r#"
+            [[payload]]
+            type = "param"
+            key1 = "branch"
+            default = "main"
+
+            [[payload]]
+            type = "param"
+            key1 = "tag"
+            required = false
+        "#);
+        let mut values = payload(&[]);
+        validate_payload(&schema, &mut values).unwrap();
+        assert_eq!(values, payload(&[("branch", "main")]));
+
+        let mut values = payload(&[("branch", "dev")]);
+        validate_payload(&schema, &mut values).unwrap();
+        assert_eq!(values, payload(&[("branch", "dev")]));
+    }
+
+    #[test]
+    fn validates_the_default_like_a_sent_value() {
+        let schema = schema(r#"
+            [[payload]]
+            type = "param"
+            key1 = "jobs"
+            value_type = "int"
+            default = "many"
+        "#);
+        let errors = validate_payload(&schema, &mut payload(&[])).unwrap_err();
+        assert_eq!(errors[0].message, "'many' is not an integer");
+    }
+
+    #[test]
+    fn normalizes_bools() {
+        let schema = schema(r#"
+            [[payload]]
+            type = "param"
+            key1 = "force"
+            value_type = "bool"
+        "#);
+        for (sent, normalized) in [("1", "true"), (" TRUE ", "true"), ("0", "false"), ("False", "false")] {
+            let mut values = payload(&[("force", sent)]);
+            validate_payload(&schema, &mut values).unwrap();
+            assert_eq!(values["force"], normalized);
+        }
+        assert!(validate_payload(&schema, &mut payload(&[("force", "yes")])).is_err());
+    }
+
+    #[test]
+    fn checks_enums() {
+        let schema = schema(r#"
+            [[payload]]
+            type = "param"
+            key1 = "env"
+            value_type = "enum"
+            values = ["staging", "production"]
+        "#);
+        assert!(validate_payload(&schema, &mut payload(&[("env", "staging")])).is_ok());
+        let errors = validate_payload(&schema, &mut payload(&[("env", "prod")])).unwrap_err();
+        assert_eq!(errors[0].message, "'prod' is not one of staging, production");
+    }
+
+    #[test]
+    fn anchors_patterns() {
+        let schema = schema(r#"
+            [[payload]]
+            type = "param"
+            key1 = "branch"
+            pattern = "[a-z]+|release-[0-9]+"
+        "#);
+        assert!(validate_payload(&schema, &mut payload(&[("branch", "main")])).is_ok());
+        assert!(validate_payload(&schema, &mut payload(&[("branch", "release-2")])).is_ok());
+        assert!(validate_payload(&schema, &mut payload(&[("branch", "main; rm -rf /")])).is_err());
+        assert!(validate_payload(&schema, &mut payload(&[("branch", "xrelease-2")])).is_err());
+    }
+
+    #[test]
+    fn returns_all_errors_together() {
+        let schema = schema(r#"
+            [[payload]]
+            type = "param"
+            key1 = "branch"
+
+            [[payload]]
+            type = "param"
+            key1 = "jobs"
+            value_type = "int"
+
+            [[payload]]
+            type = "env"
+            key1 = "TOKEN"
+            key2 = "TOKEN"
+        "#);
+        let errors = validate_payload(&schema, &mut payload(&[("jobs", "2x")])).unwrap_err();
+        let keys: Vec<&str> = errors.iter().map(|error| error.key.as_str()).collect();
+        assert_eq!(keys, ["branch", "jobs", "TOKEN"]);
+        assert_eq!(errors[0].message, "Missing required value");
+    }
+}
@@ -0,0 +1,47 @@
+use std::collections::HashMap;
+
+use crate::models::{app_state::BuildProcess, config::{Config, PayloadType}};
+
+/// shown instead of payload values that may be secret
+pub const REDACTED: &str = "***";
+
+/// whether a payload value can be shown: the unique key and the `param`/`env` values not marked `secret`
+pub fn is_visible(config: &Config, key: &str) -> bool {
+    key == config.project.build.unique_build_key
+        || config.project.build.payload.iter().any(|payload| {
+            payload.key1 == key && !payload.secret && payload.r#type != PayloadType::File
+        })
+}
+
+/// the payload of a queued or finished build as shown to operators
+pub fn redact_payload(config: &Config, payload: &HashMap<String, String>) -> HashMap<String, String> {
+    payload.iter().map(|(key, value)| {
+        let value = if is_visible(config, key) { value.clone() } else { REDACTED.to_string() };
+        (key.clone(), value)
+    }).collect()
+}
+
+/// a build as returned by the api, logs are dropped unless they were asked for
+/// the payload is redacted like the queue listing, the values the steps extracted stay visible,
+/// `file` values read back for the callback are always redacted
+pub fn build_response(config: &Config, mut build: BuildProcess, logs: bool) -> BuildProcess {
+    if !logs {
+        build.logs.clear();
+    }
+
+    for (key, value) in build.payload.iter_mut() {
+        if !is_visible(config, key) && !build.outputs.contains_key(key) {
+            *value = REDACTED.to_string();
+        }
+    }
+
+    for (key, value) in build.out_payload.iter_mut() {
+        let is_file = config.project.build.on_success_error_payload.iter()
+            .any(|payload| payload.r#type == PayloadType::File && &payload.key1 == key);
+        if is_file || !is_visible(config, key) {
+            *value = REDACTED.to_string();
+        }
+    }
+
+    build
+}
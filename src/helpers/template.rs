@@ -0,0 +1,596 @@
+//! placeholders of the command, callback url and payload file path templates
+//!
+//! * `{key}` is replaced by the value of `key`, escaped for where the template is used:
+//!   percent encoded in the callback url, as is in payload file paths
+//!   (those are still checked to stay inside the project)
+//! * commands never contain the values: each placeholder becomes a reference to an env var,
+//!   `BUILDER_PARAM_1`, `BUILDER_PARAM_2`, …, set on the command, so bash expands it exactly once
+//!   and nothing in a value is run. The quoting around the placeholder decides the form of the
+//!   reference: `"${BUILDER_PARAM_1}"` unquoted, `${BUILDER_PARAM_1}` inside `"…"` and in heredoc
+//!   bodies, `'"${BUILDER_PARAM_1}"'` inside `'…'`, so `git checkout "{branch}"` gets one word.
+//!   A placeholder in a quoted heredoc (`<<'EOF'`) or right after a backslash is an error unless it
+//!   is `raw`, bash would not expand the reference there
+//! * `{{` is a literal `{`
+//! * `${NAME}` is left to bash, as is any brace that does not wrap a plain key
+//!   (`{}`, `{a,b}`, `{print $1}`)
+//!
+//! Keys:
+//!
+//! * the `param` payloads by their `key1`
+//! * `build.id`, `build.unique_id` and `build.step`
+//! * `outputs.NAME` for every value a previous step extracted through `$BUILDER_OUTPUT`
+//!
+//! Filters are chained after the key and applied left to right, `{version|trim|upper}`:
+//!
+//! | filter          | effect                                              |
+//! |-----------------|-----------------------------------------------------|
+//! | `default:value` | `value` when the key is unknown or empty            |
+//! | `upper`         | upper case                                          |
+//! | `lower`         | lower case                                          |
+//! | `trim`          | strip surrounding whitespace                        |
+//! | `basename`      | last path component, `a/b/c.txt` gives `c.txt`     |
+//! | `dirname`       | everything before it, `a/b/c.txt` gives `a/b`       |
+//! | `if:text`       | `text` when the key is set and not empty, `false` or `0`, else nothing |
+//! | `raw`           | paste the value into the command, for values meant as shell code |
+//!
+//! `{force|if:--force}` renders `'--force'` or nothing at all, never an empty `''` argument.
+//!
+//! Filter arguments cannot contain `|`, `{` or `}`.
+//! An unknown key (without a default) or filter is an error instead of being left in the output.
+
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::LazyLock;
+
+use regex::Regex;
+
+static PLACEHOLDER: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(r"\{\{|(\$?)\{([A-Za-z_][A-Za-z0-9_.]*)((?:\|[^{}|]*)*)\}").unwrap()
+});
+
+/// prefix of the env vars carrying the values of a command
+pub const PARAM_PREFIX: &str = "BUILDER_PARAM_";
+
+/// how substituted values are escaped
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Escape {
+    /// passed through an env var and referenced, see `render_command`
+    Shell,
+    /// percent encoded for urls
+    Url,
+    /// pasted as is, for paths that are validated afterwards
+    Path,
+}
+
+/// bash quoting at a position of a command template
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Quote {
+    Unquoted,
+    Single,
+    Double,
+    /// `$'…'`, backslash escapes apply
+    AnsiC,
+    /// `$(…)`, or a plain `(` inside one
+    Subshell,
+    Backtick,
+    /// body of a heredoc, expanded unless its delimiter was quoted
+    Heredoc { expanded: bool },
+}
+
+/// a heredoc announced by `<<`, its body starts on the next line
+#[derive(Debug, Clone, Default)]
+struct Heredoc {
+    delimiter: String,
+    /// `<<-` strips leading tabs from the body lines
+    strip_tabs: bool,
+    /// `<<'EOF'`, `<<"EOF"` or `<<\EOF`, the body is taken as is
+    quoted: bool,
+}
+
+/// a heredoc delimiter being read after `<<`
+#[derive(Debug, Default)]
+struct DelimiterWord {
+    heredoc: Heredoc,
+    /// the quote the word is in
+    quote: Option<char>,
+    escaped: bool,
+}
+
+/// follows the quoting of a command template through its literal parts
+#[derive(Default)]
+struct QuoteTracker {
+    stack: Vec<Quote>,
+    /// the last character was an unquoted backslash
+    escaped: bool,
+    /// `<<` was seen, its delimiter is read next
+    delimiter: Option<DelimiterWord>,
+    /// heredocs whose body starts after the current line
+    pending: Vec<Heredoc>,
+    /// the heredoc whose body is being read, with its current line
+    body: Option<(Heredoc, String)>,
+}
+
+impl QuoteTracker {
+    fn context(&self) -> Quote {
+        if let Some((heredoc, _)) = &self.body {
+            return Quote::Heredoc { expanded: !heredoc.quoted };
+        }
+        self.stack.last().copied().unwrap_or(Quote::Unquoted)
+    }
+
+    fn feed(&mut self, text: &str) {
+        let mut chars = text.chars().peekable();
+        while let Some(c) = chars.next() {
+            if self.body.is_some() {
+                self.feed_body(c);
+                continue;
+            }
+            if self.delimiter.is_some() {
+                self.feed_delimiter(c);
+                continue;
+            }
+            if self.escaped {
+                self.escaped = false;
+                continue;
+            }
+
+            match (self.context(), c) {
+                (Quote::Single, '\'') | (Quote::AnsiC, '\'') => {
+                    self.stack.pop();
+                }
+                (Quote::Single, _) => {}
+                (_, '\\') => self.escaped = true,
+                (Quote::AnsiC, _) => {}
+                (Quote::Double, '"') | (Quote::Backtick, '`') | (Quote::Subshell, ')') => {
+                    self.stack.pop();
+                }
+                (_, '`') => self.stack.push(Quote::Backtick),
+                (_, '$') if chars.peek() == Some(&'(') => {
+                    chars.next();
+                    self.stack.push(Quote::Subshell);
+                }
+                (Quote::Double, _) => {}
+                (_, '$') if chars.peek() == Some(&'\'') => {
+                    chars.next();
+                    self.stack.push(Quote::AnsiC);
+                }
+                (_, '\'') => self.stack.push(Quote::Single),
+                (_, '"') => self.stack.push(Quote::Double),
+                (Quote::Subshell, '(') => self.stack.push(Quote::Subshell),
+                (_, '<') if chars.peek() == Some(&'<') => {
+                    chars.next();
+                    // `<<<` is a here string, not a heredoc
+                    if chars.peek() == Some(&'<') {
+                        chars.next();
+                        continue;
+                    }
+                    let strip_tabs = chars.next_if_eq(&'-').is_some();
+                    self.delimiter = Some(DelimiterWord {
+                        heredoc: Heredoc { strip_tabs, ..Heredoc::default() },
+                        ..DelimiterWord::default()
+                    });
+                }
+                (_, '\n') if !self.pending.is_empty() => self.start_body(),
+                _ => {}
+            }
+        }
+    }
+
+    /// a placeholder sits at the current position, it is part of whatever word is being read
+    fn feed_placeholder(&mut self) {
+        if let Some((_, line)) = &mut self.body {
+            // a line holding a value never ends the heredoc
+            line.push('\0');
+        } else if let Some(word) = &mut self.delimiter {
+            word.heredoc.delimiter.push('\0');
+        }
+        self.escaped = false;
+    }
+
+    fn feed_delimiter(&mut self, c: char) {
+        let word = self.delimiter.as_mut().unwrap();
+        if word.escaped {
+            word.escaped = false;
+            word.heredoc.delimiter.push(c);
+            return;
+        }
+
+        match (word.quote, c) {
+            (Some(quote), c) if c == quote => word.quote = None,
+            (Some(_), c) => word.heredoc.delimiter.push(c),
+            (None, '\'' | '"') => {
+                word.quote = Some(c);
+                word.heredoc.quoted = true;
+            }
+            (None, '\\') => {
+                word.escaped = true;
+                word.heredoc.quoted = true;
+            }
+            (None, ' ' | '\t') if word.heredoc.delimiter.is_empty() && !word.heredoc.quoted => {}
+            (None, c) if c.is_whitespace() || ";|&<>()".contains(c) => {
+                let heredoc = self.delimiter.take().unwrap().heredoc;
+                self.pending.push(heredoc);
+                // the character ending the word still belongs to the command line
+                self.feed(&c.to_string());
+            }
+            (None, c) => word.heredoc.delimiter.push(c),
+        }
+    }
+
+    fn start_body(&mut self) {
+        let heredoc = self.pending.remove(0);
+        self.body = Some((heredoc, String::new()));
+    }
+
+    fn feed_body(&mut self, c: char) {
+        let (heredoc, line) = self.body.as_mut().unwrap();
+        if c != '\n' {
+            // only an expanded body knows backslash escapes
+            self.escaped = !heredoc.quoted && c == '\\' && !self.escaped;
+            line.push(c);
+            return;
+        }
+
+        self.escaped = false;
+        let line = std::mem::take(line);
+        let line = if heredoc.strip_tabs { line.trim_start_matches('\t') } else { line.as_str() };
+        if line == heredoc.delimiter {
+            self.body = None;
+            if !self.pending.is_empty() {
+                self.start_body();
+            }
+        }
+    }
+}
+
+/// percent encode everything but the unreserved url characters
+pub fn url_encode(value: &str) -> String {
+    value.bytes().map(|byte| match byte {
+        b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => (byte as char).to_string(),
+        _ => format!("%{:02X}", byte),
+    }).collect()
+}
+
+/// the values a template can use: the `param` payloads plus `build.*` and `outputs.*`
+pub fn template_values(
+    params: &HashMap<String, String>,
+    build_id: &str,
+    unique_id: &str,
+    step: usize,
+    outputs: &HashMap<String, String>,
+) -> HashMap<String, String> {
+    let mut values = params.clone();
+    values.insert("build.id".to_string(), build_id.to_string());
+    values.insert("build.unique_id".to_string(), unique_id.to_string());
+    values.insert("build.step".to_string(), step.to_string());
+    for (key, value) in outputs {
+        values.insert(format!("outputs.{}", key), value.clone());
+    }
+    values
+}
+
+/// how a placeholder is written out after its filters ran
+struct Output {
+    escaped: bool,
+    omit_empty: bool,
+}
+
+/// whether a value switches an `if` filter on
+fn is_truthy(value: Option<&str>) -> bool {
+    value.is_some_and(|value| !matches!(value.trim(), "" | "false" | "0"))
+}
+
+/// apply one filter to the value
+fn apply_filter(filter: &str, value: Option<String>, output: &mut Output) -> Result<Option<String>, String> {
+    let (name, argument) = match filter.split_once(':') {
+        Some((name, argument)) => (name.trim(), Some(argument)),
+        None => (filter.trim(), None),
+    };
+
+    let value = match (name, argument) {
+        ("default", Some(default)) => match value {
+            Some(value) if !value.is_empty() => Some(value),
+            _ => Some(default.to_string()),
+        },
+        ("if", Some(text)) => {
+            output.omit_empty = true;
+            Some(if is_truthy(value.as_deref()) { text.to_string() } else { String::new() })
+        }
+        ("raw", None) => {
+            output.escaped = false;
+            value
+        }
+        ("upper", None) => value.map(|value| value.to_uppercase()),
+        ("lower", None) => value.map(|value| value.to_lowercase()),
+        ("trim", None) => value.map(|value| value.trim().to_string()),
+        ("basename", None) => value.map(|value| {
+            Path::new(&value).file_name().map(|name| name.to_string_lossy().into_owned()).unwrap_or_default()
+        }),
+        ("dirname", None) => value.map(|value| {
+            Path::new(&value).parent().map(|parent| parent.to_string_lossy().into_owned()).unwrap_or_default()
+        }),
+        _ => return Err(format!("Unknown filter '{}'", filter)),
+    };
+
+    Ok(value)
+}
+
+/// the reference to `$name` for where it sits in a command
+fn shell_reference(name: &str, quote: Quote, escaped: bool) -> Result<String, String> {
+    if escaped {
+        return Err("Placeholder right after a backslash".to_string());
+    }
+    match quote {
+        Quote::Unquoted | Quote::Subshell | Quote::Backtick => Ok(format!("\"${{{}}}\"", name)),
+        Quote::Double | Quote::Heredoc { expanded: true } => Ok(format!("${{{}}}", name)),
+        Quote::Single => Ok(format!("'\"${{{}}}\"'", name)),
+        Quote::AnsiC => Ok(format!("'\"${{{}}}\"$'", name)),
+        Quote::Heredoc { expanded: false } => Err("Placeholder inside a quoted heredoc".to_string()),
+    }
+}
+
+/// render a single placeholder, in commands the value is added to `params`
+fn render_placeholder(
+    key: &str,
+    filters: &str,
+    values: &HashMap<String, String>,
+    escape: Escape,
+    quotes: &QuoteTracker,
+    params: &mut Vec<(String, String)>,
+) -> Result<String, String> {
+    let mut value = values.get(key).cloned();
+    let mut output = Output { escaped: true, omit_empty: false };
+
+    for filter in filters.split('|').skip(1) {
+        value = apply_filter(filter, value, &mut output)
+            .map_err(|e| format!("{} in {{{}{}}}", e, key, filters))?;
+    }
+
+    let value = value.ok_or_else(|| format!("Unknown placeholder {{{}}}", key))?;
+
+    if !output.escaped || (output.omit_empty && value.is_empty()) {
+        return Ok(value);
+    }
+
+    match escape {
+        Escape::Shell => {
+            let name = format!("{}{}", PARAM_PREFIX, params.len() + 1);
+            let reference = shell_reference(&name, quotes.context(), quotes.escaped)
+                .map_err(|e| format!("{} in {{{}{}}}, add |raw to paste it as is", e, key, filters))?;
+            params.push((name, value));
+            Ok(reference)
+        }
+        Escape::Url => Ok(url_encode(&value)),
+        Escape::Path => Ok(value),
+    }
+}
+
+/// render a template, see the module docs for the syntax
+/// commands are rendered through `render_command`, here they would lose their values
+pub fn render_template(template: &str, values: &HashMap<String, String>, escape: Escape) -> Result<String, String> {
+    render(template, values, escape, &mut Vec::new())
+}
+
+fn render(template: &str, values: &HashMap<String, String>, escape: Escape, params: &mut Vec<(String, String)>) -> Result<String, String> {
+    let mut rendered = String::with_capacity(template.len());
+    let mut quotes = QuoteTracker::default();
+    let mut last = 0;
+
+    for caps in PLACEHOLDER.captures_iter(template) {
+        let placeholder = caps.get(0).unwrap();
+        let literal = &template[last..placeholder.start()];
+        quotes.feed(literal);
+        rendered.push_str(literal);
+        last = placeholder.end();
+
+        // `{{` is a literal brace, `${NAME}` belongs to bash
+        if placeholder.as_str() == "{{" || &caps[1] == "$" {
+            let text = if placeholder.as_str() == "{{" { "{" } else { placeholder.as_str() };
+            quotes.feed(text);
+            rendered.push_str(text);
+            continue;
+        }
+
+        rendered.push_str(&render_placeholder(&caps[2], &caps[3], values, escape, &quotes, params)?);
+        quotes.feed_placeholder();
+    }
+
+    rendered.push_str(&template[last..]);
+    Ok(rendered)
+}
+
+/// a command with the values of its placeholders, they are set as env vars when it runs
+#[derive(Debug, Clone, PartialEq)]
+pub struct RenderedCommand {
+    pub command: String,
+    pub params: Vec<(String, String)>,
+}
+
+/// render a command, its placeholders refer to the `BUILDER_PARAM_n` env vars in `params`
+pub fn render_command(template: &str, values: &HashMap<String, String>) -> Result<RenderedCommand, String> {
+    let mut params = Vec::new();
+    let command = render(template, values, Escape::Shell, &mut params)?;
+    Ok(RenderedCommand { command, params })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn values(pairs: &[(&str, &str)]) -> HashMap<String, String> {
+        pairs.iter().map(|(key, value)| (key.to_string(), value.to_string())).collect()
+    }
+
+    /// what bash prints for the rendered command
+    fn run(command: &RenderedCommand) -> String {
+        let output = std::process::Command::new("bash")
+            .arg("-c")
+            .arg(&command.command)
+            .envs(command.params.iter().cloned())
+            .output()
+            .unwrap();
+        String::from_utf8(output.stdout).unwrap()
+    }
+
+    const HOSTILE: &str = "; echo INJECTED '\" $(echo sub) `echo tick` \\ ${HOME}";
+
+    #[test]
+    fn keeps_values_literal_in_every_quote_context() {
+        let values = values(&[("branch", HOSTILE)]);
+        for template in [
+            "printf %s {branch}",
+            "printf %s '{branch}'",
+            "printf %s \"{branch}\"",
+            "printf %s $'{branch}'",
+            "printf %s \"$(printf %s {branch})\"",
+            "printf %s \"$(printf %s \"{branch}\")\"",
+            "printf %s \"`printf %s {branch}`\"",
+            "printf %s \"${UNSET:-{branch}}\"",
+        ] {
+            let command = render_command(template, &values).unwrap();
+            assert!(!command.command.contains("INJECTED"), "{} rendered as {}", template, command.command);
+            assert_eq!(run(&command), HOSTILE, "{} rendered as {}", template, command.command);
+        }
+    }
+
+    #[test]
+    fn keeps_values_literal_in_heredoc_bodies() {
+        let values = values(&[("branch", HOSTILE)]);
+        for template in [
+            "cat <<EOF\nBRANCH={branch}\nEOF",
+            "cat <<-EOF\n\tBRANCH={branch}\n\tEOF",
+            "cat <<EOF; cat <<END\nEOF\nBRANCH={branch}\nEND",
+        ] {
+            let command = render_command(template, &values).unwrap();
+            assert_eq!(run(&command), format!("BRANCH={}\n", HOSTILE), "{} rendered as {}", template, command.command);
+        }
+
+        // neither a line starting with the delimiter nor an unbalanced quote ends the body
+        let command = render_command("cat <<EOF\nEOF is not the end\n\"BRANCH={branch}\nEOF", &values).unwrap();
+        assert_eq!(run(&command), format!("EOF is not the end\n\"BRANCH={}\n", HOSTILE));
+
+        let command = render_command("cat > /dev/null <<EOF\nBRANCH={branch}\nEOF\nprintf %s {branch}", &values).unwrap();
+        assert_eq!(run(&command), HOSTILE);
+    }
+
+    #[test]
+    fn never_puts_a_value_into_the_command() {
+        let values = values(&[("branch", "$(touch /tmp/pwned)")]);
+        for template in [
+            "cat > .env <<EOF\nBRANCH={branch}\nEOF",
+            "# it's a comment\necho {branch}",
+            "echo \"${VAR:-{branch}}\"",
+        ] {
+            let command = render_command(template, &values).unwrap();
+            assert!(!command.command.contains("pwned"), "{} rendered as {}", template, command.command);
+            assert_eq!(command.params, [("BUILDER_PARAM_1".to_string(), "$(touch /tmp/pwned)".to_string())]);
+        }
+    }
+
+    #[test]
+    fn keeps_the_surrounding_text_of_a_quoted_placeholder() {
+        let values = values(&[("branch", "main")]);
+        let command = render_command("printf %s \"refs/{branch} x\" '[{branch}]'", &values).unwrap();
+        assert_eq!(command.command, "printf %s \"refs/${BUILDER_PARAM_1} x\" '['\"${BUILDER_PARAM_2}\"']'");
+        assert_eq!(run(&command), "refs/main x[main]");
+    }
+
+    #[test]
+    fn rejects_placeholders_in_quoted_heredocs_or_after_a_backslash() {
+        let values = values(&[("branch", "main")]);
+        assert!(render_command("cat <<'EOF'\n{branch}\nEOF", &values).is_err());
+        assert!(render_command("cat <<\"EOF\"\n{branch}\nEOF", &values).is_err());
+        assert!(render_command("cat <<\\EOF\n{branch}\nEOF", &values).is_err());
+        assert!(render_command("cat <<EOF\n\\{branch}\nEOF", &values).is_err());
+        assert!(render_command("echo \\{branch}", &values).is_err());
+        assert_eq!(render_command("cat <<'EOF'\n{branch|raw}\nEOF", &values).unwrap().command, "cat <<'EOF'\nmain\nEOF");
+        assert!(render_command("cat <<<{branch}", &values).is_ok());
+    }
+
+    #[test]
+    fn escaped_quotes_do_not_open_a_context() {
+        let values = values(&[("branch", "a b")]);
+        let command = render_command("printf '%s|' \\' {branch} \"\\\"\" {branch}", &values).unwrap();
+        assert_eq!(run(&command), "'|a b|\"|a b|");
+    }
+
+    fn render(template: &str, escape: Escape) -> Result<String, String> {
+        let values = values(&[
+            ("name", "My App"),
+            ("padded", "  v1.2  "),
+            ("file", "a/b/c.txt"),
+            ("empty", ""),
+            ("on", "true"),
+            ("off", "false"),
+            ("zero", "0"),
+        ]);
+        render_template(template, &values, escape)
+    }
+
+    #[test]
+    fn applies_every_filter() {
+        assert_eq!(render("{name|upper}", Escape::Path).unwrap(), "MY APP");
+        assert_eq!(render("{name|lower}", Escape::Path).unwrap(), "my app");
+        assert_eq!(render("{padded|trim}", Escape::Path).unwrap(), "v1.2");
+        assert_eq!(render("{file|basename}", Escape::Path).unwrap(), "c.txt");
+        assert_eq!(render("{file|dirname}", Escape::Path).unwrap(), "a/b");
+        assert_eq!(render("{empty|default:none}", Escape::Path).unwrap(), "none");
+        assert_eq!(render("{name|default:none}", Escape::Path).unwrap(), "My App");
+        assert_eq!(render("{on|if:--force}", Escape::Path).unwrap(), "--force");
+        assert_eq!(render("{name|raw}", Escape::Shell).unwrap(), "My App");
+    }
+
+    #[test]
+    fn chains_filters_left_to_right() {
+        assert_eq!(render("{padded|trim|upper}", Escape::Path).unwrap(), "V1.2");
+        assert_eq!(render("{missing|default: x |trim}", Escape::Path).unwrap(), "x");
+    }
+
+    #[test]
+    fn uses_the_default_for_a_missing_key() {
+        assert_eq!(render("{missing|default:main}", Escape::Path).unwrap(), "main");
+        assert_eq!(render("{missing|default:}", Escape::Path).unwrap(), "");
+    }
+
+    #[test]
+    fn omits_an_empty_if_argument() {
+        for key in ["empty", "off", "zero", "missing"] {
+            let template = format!("git push {{{}|if:--force}} origin", key);
+            let command = render_command(&template, &values(&[("empty", ""), ("off", "false"), ("zero", "0")])).unwrap();
+            assert_eq!(command.command, "git push  origin");
+            assert!(command.params.is_empty());
+        }
+        let command = render_command("git push {on|if:--force} origin", &values(&[("on", "true")])).unwrap();
+        assert_eq!(command.command, "git push \"${BUILDER_PARAM_1}\" origin");
+        assert_eq!(command.params, [("BUILDER_PARAM_1".to_string(), "--force".to_string())]);
+    }
+
+    #[test]
+    fn keeps_double_braces_and_bash_variables() {
+        assert_eq!(render("{{name}", Escape::Shell).unwrap(), "{name}");
+        assert_eq!(render("echo ${HOME} ${name}", Escape::Shell).unwrap(), "echo ${HOME} ${name}");
+        assert_eq!(render("awk '{print $1}' {} {a,b}", Escape::Shell).unwrap(), "awk '{print $1}' {} {a,b}");
+    }
+
+    #[test]
+    fn rejects_unknown_keys_and_filters() {
+        assert_eq!(render("echo {missing}", Escape::Shell).unwrap_err(), "Unknown placeholder {missing}");
+        assert_eq!(render("echo {name|shout}", Escape::Shell).unwrap_err(), "Unknown filter 'shout' in {name|shout}");
+        assert!(render("echo {name|if}", Escape::Shell).is_err());
+        assert!(render("echo {name|upper:x}", Escape::Shell).is_err());
+    }
+
+    #[test]
+    fn escapes_for_urls_and_paths() {
+        assert_eq!(render("https://ci.test/done?app={name}&f={file}", Escape::Url).unwrap(), "https://ci.test/done?app=My%20App&f=a%2Fb%2Fc.txt");
+        assert_eq!(render("uploads/{file|dirname}/{name}.env", Escape::Path).unwrap(), "uploads/a/b/My App.env");
+        assert_eq!(url_encode("a-b_c.d~é"), "a-b_c.d~%C3%A9");
+    }
+
+    #[test]
+    fn passes_shell_values_as_one_word() {
+        let command = render_command("printf '%s|' {name} {name}", &values(&[("name", "it's a b")])).unwrap();
+        assert_eq!(command.command, "printf '%s|' \"${BUILDER_PARAM_1}\" \"${BUILDER_PARAM_2}\"");
+        assert_eq!(run(&command), "it's a b|it's a b|");
+    }
+}
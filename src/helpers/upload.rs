@@ -0,0 +1,252 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::os::unix::fs::{DirBuilderExt, OpenOptionsExt};
+use std::path::{Path, PathBuf};
+
+use actix_multipart::Multipart;
+use actix_web::{HttpRequest, web};
+use base64::{Engine, engine::general_purpose::STANDARD};
+use futures_util::StreamExt;
+use tokio::io::AsyncWriteExt;
+
+use crate::helpers::utils::{generate_token, private_dir};
+use crate::models::config::{Payload, PayloadType};
+
+/// suffix of a json field carrying a `file` payload as base64, `keystore.base64`
+const BASE64_SUFFIX: &str = ".base64";
+
+/// the upload directory inside the private directory of the process
+fn upload_dir() -> io::Result<PathBuf> {
+    let dir = private_dir()?.join("uploads");
+    fs::DirBuilder::new().recursive(true).mode(0o700).create(&dir)?;
+    Ok(dir)
+}
+
+/// a `file` payload received as bytes, kept in a temporary file until the build is queued
+/// the temporary file is removed when the upload is dropped without being persisted
+pub struct UploadedFile {
+    path: PathBuf,
+    pub file_name: Option<String>,
+    pub size: u64,
+}
+
+impl UploadedFile {
+    /// a new empty temporary file for an upload, readable by this user only
+    async fn create() -> io::Result<(Self, tokio::fs::File)> {
+        let path = upload_dir()?.join(generate_token(32));
+        let file = tokio::fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .mode(0o600)
+            .open(&path)
+            .await?;
+
+        Ok((Self { path, file_name: None, size: 0 }, file))
+    }
+
+    /// a temporary copy of a kept upload, persisted like a fresh one
+    pub async fn copy_of(source: &Path) -> io::Result<Self> {
+        let (mut upload, mut file) = Self::create().await?;
+        let mut source = tokio::fs::File::open(source).await?;
+        upload.size = tokio::io::copy(&mut source, &mut file).await?;
+        file.flush().await?;
+        Ok(upload)
+    }
+
+    /// move the upload to its destination, creating the parent directories
+    /// the destination only ever appears complete and keeps the 0600 permissions,
+    /// the copy between filesystems runs on the blocking pool
+    pub async fn persist(&self, destination: &Path) -> io::Result<()> {
+        let source = self.path.clone();
+        let destination = destination.to_path_buf();
+        tokio::task::spawn_blocking(move || move_file(&source, &destination))
+            .await
+            .map_err(io::Error::other)?
+    }
+}
+
+/// move a file, copying it when the destination is on another filesystem
+fn move_file(source: &Path, destination: &Path) -> io::Result<()> {
+    let parent = destination.parent().ok_or_else(|| io::Error::other("Destination has no parent directory"))?;
+    fs::create_dir_all(parent)?;
+
+    if fs::rename(source, destination).is_ok() {
+        return Ok(());
+    }
+
+    // the source is removed by the caller
+    copy_file(source, destination)
+}
+
+/// copy a file next to the destination first, so the destination only ever appears complete
+/// the copy is readable by this user only, the parent directories are created
+pub fn copy_file(source: &Path, destination: &Path) -> io::Result<()> {
+    let parent = destination.parent().ok_or_else(|| io::Error::other("Destination has no parent directory"))?;
+    fs::create_dir_all(parent)?;
+
+    let partial = parent.join(format!(".upload-{}", generate_token(16)));
+    let copied = fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(&partial)
+        .and_then(|mut file| io::copy(&mut fs::File::open(source)?, &mut file))
+        .and_then(|_| fs::rename(&partial, destination));
+    if copied.is_err() {
+        let _ = fs::remove_file(&partial);
+    }
+    copied.map(|_| ())
+}
+
+impl Drop for UploadedFile {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+/// the fields and files of a build request
+#[derive(Default)]
+pub struct BuildBody {
+    pub payload: HashMap<String, String>,
+    pub files: HashMap<String, UploadedFile>,
+}
+
+/// why a build request body could not be read
+pub enum BodyError {
+    /// a field, file or the json body is over `max_upload_size`
+    TooLarge(String),
+    Invalid(String),
+    /// an upload could not be written to its temporary file
+    Storage(String),
+}
+
+impl std::fmt::Display for BodyError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            BodyError::TooLarge(message) | BodyError::Invalid(message) | BodyError::Storage(message) => write!(f, "{}", message),
+        }
+    }
+}
+
+/// whether the request is `multipart/form-data`
+fn is_multipart(req: &HttpRequest) -> bool {
+    req.headers()
+        .get(actix_web::http::header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| value.starts_with("multipart/form-data"))
+}
+
+/// whether `key` is a configured `file` payload
+fn is_file_payload(schema: &[Payload], key: &str) -> bool {
+    schema.iter().any(|payload| payload.r#type == PayloadType::File && payload.key1 == key)
+}
+
+/// read a multipart body, file parts are streamed to temporary files
+/// `max_size` limits all parts together
+async fn read_multipart(req: &HttpRequest, body: web::Payload, schema: &[Payload], max_size: u64) -> Result<BuildBody, BodyError> {
+    let mut multipart = Multipart::new(req.headers(), body);
+    let mut build_body = BuildBody::default();
+    let mut total: u64 = 0;
+    let mut count = |name: &str, len: usize| {
+        total += len as u64;
+        if total > max_size {
+            return Err(BodyError::TooLarge(format!("Body is larger than {} bytes at {}", max_size, name)));
+        }
+        Ok(())
+    };
+
+    while let Some(field) = multipart.next().await {
+        let mut field = field.map_err(|e| BodyError::Invalid(format!("Invalid multipart body: {}", e)))?;
+        let Some(name) = field.name().map(str::to_string) else {
+            return Err(BodyError::Invalid("Multipart field without a name".to_string()));
+        };
+        let file_name = field.content_disposition()
+            .and_then(|disposition| disposition.get_filename())
+            .map(str::to_string);
+
+        // plain form fields
+        if file_name.is_none() {
+            let mut value = Vec::new();
+            while let Some(chunk) = field.next().await {
+                let chunk = chunk.map_err(|e| BodyError::Invalid(format!("Failed to read field {}: {}", name, e)))?;
+                count(&name, chunk.len())?;
+                value.extend_from_slice(&chunk);
+            }
+            let value = String::from_utf8(value).map_err(|_| BodyError::Invalid(format!("Field {} is not valid utf-8", name)))?;
+            build_body.payload.insert(name, value);
+            continue;
+        }
+
+        if !is_file_payload(schema, &name) {
+            return Err(BodyError::Invalid(format!("Unexpected file {}", name)));
+        }
+
+        let (mut upload, mut file) = UploadedFile::create().await
+            .map_err(|e| BodyError::Storage(format!("Failed to store upload {}: {}", name, e)))?;
+        upload.file_name = file_name;
+
+        while let Some(chunk) = field.next().await {
+            let chunk = chunk.map_err(|e| BodyError::Invalid(format!("Failed to read upload {}: {}", name, e)))?;
+            count(&name, chunk.len())?;
+            upload.size += chunk.len() as u64;
+            file.write_all(&chunk).await.map_err(|e| BodyError::Storage(format!("Failed to store upload {}: {}", name, e)))?;
+        }
+        file.flush().await.map_err(|e| BodyError::Storage(format!("Failed to store upload {}: {}", name, e)))?;
+
+        build_body.files.insert(name, upload);
+    }
+
+    Ok(build_body)
+}
+
+/// read a json body, `<key>.base64` fields of `file` payloads are decoded to temporary files
+async fn read_json(mut body: web::Payload, schema: &[Payload], max_size: u64) -> Result<BuildBody, BodyError> {
+    let mut bytes = web::BytesMut::new();
+    while let Some(chunk) = body.next().await {
+        let chunk = chunk.map_err(|e| BodyError::Invalid(format!("Failed to read body: {}", e)))?;
+        if (bytes.len() + chunk.len()) as u64 > max_size {
+            return Err(BodyError::TooLarge(format!("Body is larger than {} bytes", max_size)));
+        }
+        bytes.extend_from_slice(&chunk);
+    }
+
+    let fields: HashMap<String, String> = serde_json::from_slice(&bytes)
+        .map_err(|e| BodyError::Invalid(format!("Invalid json body: {}", e)))?;
+
+    let mut build_body = BuildBody::default();
+    for (key, value) in fields {
+        let Some(name) = key.strip_suffix(BASE64_SUFFIX).filter(|name| is_file_payload(schema, name)) else {
+            build_body.payload.insert(key, value);
+            continue;
+        };
+
+        let content = STANDARD.decode(value.trim()).map_err(|e| BodyError::Invalid(format!("Invalid base64 in {}: {}", key, e)))?;
+
+        let (mut upload, mut file) = UploadedFile::create().await
+            .map_err(|e| BodyError::Storage(format!("Failed to store upload {}: {}", name, e)))?;
+        upload.size = content.len() as u64;
+        file.write_all(&content).await.map_err(|e| BodyError::Storage(format!("Failed to store upload {}: {}", name, e)))?;
+        file.flush().await.map_err(|e| BodyError::Storage(format!("Failed to store upload {}: {}", name, e)))?;
+
+        build_body.files.insert(name.to_string(), upload);
+    }
+
+    Ok(build_body)
+}
+
+/// read the body of a build request, either json or `multipart/form-data`
+/// uploaded files show up in the payload by their file name, so presence and patterns are still checked
+pub async fn read_build_body(req: &HttpRequest, body: web::Payload, schema: &[Payload], max_size: u64) -> Result<BuildBody, BodyError> {
+    let mut build_body = if is_multipart(req) {
+        read_multipart(req, body, schema, max_size).await?
+    } else {
+        read_json(body, schema, max_size).await?
+    };
+
+    for (key, upload) in &build_body.files {
+        build_body.payload.insert(key.clone(), upload.file_name.clone().unwrap_or_default());
+    }
+
+    Ok(build_body)
+}
@@ -1,24 +1,24 @@
 use std::collections::HashMap;
-use std::sync::Arc;
+use std::sync::{Arc, OnceLock};
 use std::time::Duration;
 
 use rand::{distributions::Alphanumeric, Rng};
-use regex::Regex;
 use reqwest::Client;
 use tokio::io::{AsyncBufReadExt, BufReader};
 use tokio::process::{ChildStderr, ChildStdout};
+use tokio::sync::Notify;
 use tokio::time;
 use std::fs::{self, File};
 use std::io::{self, Write};
-use std::path::{Path, PathBuf};
+use std::os::unix::fs::DirBuilderExt;
+use std::path::{Component, Path, PathBuf};
 use chrono::Local;
 use crate::models::app_state::ChannelMessage;
-use crate::models::app_state::{AppState, BuildLog};
-use crate::models::config::PayloadType;
+use crate::models::app_state::{AppState, BuildHandle, BuildLog};
+use crate::models::config::{Config, Payload, PayloadType};
 use crate::models::status::Status;
 
 ///generate a random token
-
 pub fn generate_token(len: usize) -> String {
     rand::thread_rng()
         .sample_iter(&Alphanumeric)
@@ -86,110 +86,175 @@ pub fn create_file_with_dirs_and_content(file_path: &str, content: &str) -> io::
 }
 /// join path with secure path
 /// if the path is not secure, it will be returned as None (If outside the base path)
+/// the path does not have to exist yet: `..` is resolved lexically and never above the base,
+/// then every existing ancestor is followed through its symlinks and has to stay inside the base
 pub fn secure_join_path(base: &str, user_input: &str) -> Option<String> {
     // Canonicalize base directory
     let base = fs::canonicalize(base).ok()?;
-    // Join and canonicalize the full path
-    let full_path = fs::canonicalize(base.join(user_input)).ok()?;
-    // Ensure full path is within base
-    if full_path.starts_with(&base) {
-        let str_path = full_path.to_str()?;
-        // Ensure path is not empty
-        if str_path.is_empty() {
+
+    // Resolve `.` and `..` without touching the filesystem
+    let mut relative: Vec<&std::ffi::OsStr> = Vec::new();
+    for component in Path::new(user_input).components() {
+        match component {
+            Component::Normal(name) => relative.push(name),
+            Component::CurDir => {}
+            Component::ParentDir => {
+                relative.pop()?;
+            }
+            // absolute paths are never inside the base
+            Component::RootDir | Component::Prefix(_) => return None,
+        }
+    }
+
+    // Ensure path is not the base itself
+    if relative.is_empty() {
+        return None;
+    }
+
+    // Follow the existing part, the rest is created below the last existing directory
+    let mut full_path = base.clone();
+    for (index, name) in relative.iter().enumerate() {
+        let next = full_path.join(name);
+        if fs::symlink_metadata(&next).is_err() {
+            full_path = relative[index..].iter().fold(full_path, |path, name| path.join(name));
+            break;
+        }
+
+        // a dangling symlink fails here, writing through it could land anywhere
+        full_path = fs::canonicalize(&next).ok()?;
+        if !full_path.starts_with(&base) {
             return None;
         }
-        Some(str_path.into())
-    } else {
-        None
     }
+
+    if full_path == base {
+        return None;
+    }
+
+    Some(full_path.to_str()?.into())
 }
 
 
+/// the `param` payloads of a request by their `key1`
+pub fn param_values(payloads: &[Payload], values: &HashMap<String, String>) -> HashMap<String, String> {
+    payloads.iter()
+        .filter(|payload| payload.r#type == PayloadType::Param)
+        .filter_map(|payload| values.get(&payload.key1).map(|value| (payload.key1.clone(), value.clone())))
+        .collect()
+}
+
 /// extract payload from the request
-pub async fn extract_payload(state: &Arc<AppState>,env_map:&mut HashMap<String,String>,param_map:&mut HashMap<String,String>) {
+pub async fn extract_payload(state: &Arc<AppState>,config: &Config,build_id: &str,env_map:&mut HashMap<String,String>,param_map:&mut HashMap<String,String>) {
+
+    let payload_values = {
+        let current_builds = state.builds.current_builds.lock().await;
+        match current_builds.get(build_id) {
+            Some(build) => build.payload.clone(),
+            None => return,
+        }
+    };
 
+    for payload in &config.project.build.payload {
 
-    for payload in &state.config.project.build.payload {
+        // optional values that were not sent
+        let Some(value) = payload_values.get(payload.key1.as_str()) else {
+            continue;
+        };
 
         if PayloadType::Param == payload.r#type {
-            let mut  current_build = state.builds.current_build.lock().await;
-            let  current_build = current_build.as_mut().unwrap();
-            let param_value = current_build.payload.get(payload.key1.as_str()).unwrap();
-            param_map.insert(payload.key1.to_string(), param_value.to_string());
+            param_map.insert(payload.key1.to_string(), value.to_string());
             continue;
         }
 
         if payload.r#type != PayloadType::Env{
             continue;
         }
-        let env_name = if payload.key2.is_some() {
-            payload.key2.as_ref().unwrap()
-        } else {
-            payload.key1.as_str()
-        };
+        let env_name = payload.key2.as_deref().unwrap_or(payload.key1.as_str());
 
-        let mut  current_build = state.builds.current_build.lock().await;
-        let  current_build = current_build.as_mut().unwrap();
-        let env_value = current_build.payload.get(payload.key1.as_str()).unwrap();
+        let env_value = value;
         env_map.insert(env_name.to_string(), env_value.to_string());
     }
 }
 
+/// where the output of a running command is sent to
+#[derive(Clone, Copy)]
+pub struct OutputTarget<'a> {
+    pub state: &'a Arc<AppState>,
+    pub build_id: &'a str,
+    pub handle: &'a BuildHandle,
+    pub step: usize,
+    pub attempt: u32,
+    pub send_to_sock: bool,
+    pub bypass_termination: bool,
+}
+
+/// push the buffered logs to the build and its socket
+async fn flush_logs(target: &OutputTarget<'_>, buffer: &mut Vec<BuildLog>) {
+    if buffer.is_empty() {
+        return;
+    }
+
+    // Lock once and push all buffered logs
+    let mut current_builds = target.state.builds.current_builds.lock().await;
+    if let Some(build) = current_builds.get_mut(target.build_id) {
+        build.logs.extend(buffer.iter().cloned());
+    }
+    drop(current_builds);
+
+    if target.send_to_sock {
+        let json_str = serde_json::to_string(&buffer).unwrap();
+        let _ = target.handle.sender.send(ChannelMessage::Data(json_str));
+    }
+
+    buffer.clear();
+}
+
+/// add a single log for the current step and attempt to the build and its socket
+pub async fn push_build_log(target: &OutputTarget<'_>, status: Status, message: String) {
+    let log = BuildLog {
+        timestamp: chrono::Utc::now(),
+        status,
+        step: target.step,
+        attempt: target.attempt,
+        message,
+    };
+    flush_logs(target, &mut vec![log]).await;
+}
+
+/// flush interval of the output buffers, never below 500ms
+fn flush_interval(config: &Config) -> time::Interval {
+    let flush_interval = if config.project.flush_interval >=500{
+            config.project.flush_interval
+        }
+        else{
+            500
+        };
+
+    time::interval(Duration::from_millis(flush_interval as u64))
+}
+
 /// read stdout of the command to build logs and send to socket
 pub async fn read_stdout(
     stdout: ChildStdout,
-    step: usize,
-    state: &Arc<AppState>,
-    send_to_sock: bool,
-    bypass_termination: bool,
-    extract_envs: &Vec<String>,
-    env_map: &mut HashMap<String, String>,
+    target: &OutputTarget<'_>,
+    activity: &Notify,
 ) {
     let reader = &mut BufReader::new(stdout);
     let mut lines = reader.lines();
 
-    let mut is_env = false;
     let mut buffer: Vec<BuildLog> = Vec::new();
 
+    let mut interval = flush_interval(&target.handle.config);
 
-    let flush_interval = if state.config.project.flush_interval >=500{
-            state.config.project.flush_interval
-        }
-        else{
-            500
-        };
-
-    // Interval timer for flushing logs every 200ms (adjust as needed)
-    let mut interval = time::interval(Duration::from_millis(flush_interval as u64));
-
-    // Set flush interval (e.g. 1 second)
+    let cancelled = target.handle.cancel.cancelled();
+    tokio::pin!(cancelled);
 
     loop {
         tokio::select! {
             line_opt = lines.next_line() => {
                 match line_opt {
                     Ok(Some(line)) => {
-                        if line.contains("+_+_+_") {
-                            is_env = true;
-                            continue;
-                        }
-
-                        if is_env {
-                            if let Some((key, value)) = line.split_once('=') {
-                                if extract_envs.contains(&key.to_string()) {
-                                    let mut current_build = state.builds.current_build.lock().await;
-                                    if let Some(build) = current_build.as_mut() {
-                                        build.payload.insert(key.to_string(), value.to_string());
-                                    }
-                                    env_map.insert(key.to_string(), value.to_string());
-                                }
-                            }
-                            continue;
-                        }
-
-                        if !bypass_termination && *state.is_terminated.lock().await {
-                            break;
-                        }
+                        activity.notify_one();
 
                         let trimmed = line.trim();
                         if trimmed.is_empty() {
@@ -199,7 +264,8 @@ pub async fn read_stdout(
                         let log = BuildLog {
                             timestamp: chrono::Utc::now(),
                             status: Status::Success,
-                            step,
+                            step: target.step,
+                            attempt: target.attempt,
                             message: trimmed.to_string(),
                         };
 
@@ -218,52 +284,23 @@ pub async fn read_stdout(
                 }
             }
             _ = interval.tick() => {
-                if !buffer.is_empty() {
-                    // Lock once and push all buffered logs
-                    let mut current_build = state.builds.current_build.lock().await;
-                    if let Some(build) = current_build.as_mut() {
-                        for log in &buffer {
-                            build.logs.push(log.clone());
-                        }
-                    }
-                    drop(current_build);
-
-                    if send_to_sock {
-                        let json_str = serde_json::to_string(&buffer).unwrap();
-                        let _ = state.build_sender.send(ChannelMessage::Data(json_str));
-                    }
-
-                    buffer.clear();
-                }
+                flush_logs(target, &mut buffer).await;
+            }
+            _ = &mut cancelled, if !target.bypass_termination => {
+                break;
             }
         }
     }
 
     // Send remaining buffered logs on EOF or termination
-    if !buffer.is_empty() {
-        let mut current_build = state.builds.current_build.lock().await;
-        if let Some(build) = current_build.as_mut() {
-            for log in &buffer {
-                build.logs.push(log.clone());
-            }
-        }
-        drop(current_build);
-
-        if send_to_sock {
-            let json_str = serde_json::to_string(&buffer).unwrap();
-            let _ = state.build_sender.send(ChannelMessage::Data(json_str));
-        }
-    }
+    flush_logs(target, &mut buffer).await;
 }
 
 /// read stderr of the command to build logs and send to socket
-
 pub async fn read_stderr(
     stderr: ChildStderr,
-    step: usize,
-    state: &Arc<AppState>,
-    send_to_sock: bool,
-    bypass_termination: bool,
+    target: &OutputTarget<'_>,
+    activity: &Notify,
 ) {
     let reader = &mut BufReader::new(stderr);
     let mut lines = reader.lines();
@@ -271,24 +308,17 @@ pub async fn read_stderr(
     // Buffer to hold logs before sending
     let mut buffer: Vec<BuildLog> = Vec::new();
 
-    let flush_interval = if state.config.project.flush_interval >=500{
-            state.config.project.flush_interval
-        }
-        else{
-            500
-        };
+    let mut interval = flush_interval(&target.handle.config);
 
-    // Interval timer for flushing logs every 200ms (adjust as needed)
-    let mut interval = time::interval(Duration::from_millis(flush_interval as u64));
+    let cancelled = target.handle.cancel.cancelled();
+    tokio::pin!(cancelled);
 
     loop {
         tokio::select! {
             line_opt = lines.next_line() => {
                 match line_opt {
                     Ok(Some(line)) => {
-                        if !bypass_termination && *state.is_terminated.lock().await {
-                            break;
-                        }
+                        activity.notify_one();
 
                         let trimmed = line.trim();
                         if trimmed.is_empty() {
@@ -298,7 +328,8 @@ pub async fn read_stderr(
                         let log = BuildLog {
                             timestamp: chrono::Utc::now(),
                             status: Status::Error,
-                            step,
+                            step: target.step,
+                            attempt: target.attempt,
                             message: trimmed.to_string(),
                         };
 
@@ -317,60 +348,97 @@ pub async fn read_stderr(
                 }
             }
             _ = interval.tick() => {
-                if !buffer.is_empty() {
-                    // Lock current_build once per flush
-                    let mut current_build = state.builds.current_build.lock().await;
-                    if let Some(build) = current_build.as_mut() {
-                        for log in &buffer {
-                            build.logs.push(log.clone());
-                        }
-                    }
-                    drop(current_build);
-
-                    // Send batch if requested
-                    if send_to_sock {
-                        let json_str = serde_json::to_string(&buffer).unwrap();
-                        let _ = state.build_sender.send(ChannelMessage::Data(json_str));
-                    }
-
-                    buffer.clear();
-                }
+                flush_logs(target, &mut buffer).await;
+            }
+            _ = &mut cancelled, if !target.bypass_termination => {
+                break;
             }
         }
     }
 
     // Send any remaining logs after EOF or termination
-    if !buffer.is_empty() {
-        let mut current_build = state.builds.current_build.lock().await;
-        if let Some(build) = current_build.as_mut() {
-            for log in &buffer {
-                build.logs.push(log.clone());
-            }
-        }
-        drop(current_build);
-        if send_to_sock {
-            let json_str = serde_json::to_string(&buffer).unwrap();
-            let _ = state.build_sender.send(ChannelMessage::Data(json_str));
-        }
+    flush_logs(target, &mut buffer).await;
+}
+
+/// private directory of this process, see `private_dir`
+static PRIVATE_DIR: OnceLock<PathBuf> = OnceLock::new();
+
+/// the temporary directory of this process for uploads and step outputs, created on first use
+/// and readable by this user only; a fresh name per process, so nothing another user planted
+/// in the shared temp directory is ever reused
+pub fn private_dir() -> io::Result<&'static Path> {
+    if let Some(dir) = PRIVATE_DIR.get() {
+        return Ok(dir);
+    }
+    let dir = std::env::temp_dir().join(format!("app_builder-{}", generate_token(16)));
+    fs::DirBuilder::new().mode(0o700).create(&dir)?;
+    // a concurrent first caller may have won, its directory is used and this one dropped
+    if let Err(dir) = PRIVATE_DIR.set(dir) {
+        let _ = fs::remove_dir(dir);
     }
+    Ok(PRIVATE_DIR.get().unwrap())
 }
 
-    
+/// remove the private directory with whatever is left in it, on shutdown
+pub fn remove_private_dir() {
+    if let Some(dir) = PRIVATE_DIR.get() {
+        let _ = fs::remove_dir_all(dir);
+    }
+}
 
+/// directory holding the `$BUILDER_OUTPUT` files of a build
+pub fn builder_output_dir(build_id: &str) -> io::Result<PathBuf> {
+    Ok(private_dir()?.join("outputs").join(build_id))
+}
 
+/// parse a `$BUILDER_OUTPUT` file
+/// every line is `KEY=value`, multi-line values use a heredoc style delimiter:
+///
+/// ```text
+/// KEY<<EOF
+/// first line
+/// second line
+/// EOF
+/// ```
+///
+/// a heredoc without its closing delimiter, or with an empty one, is dropped
+pub fn parse_builder_output(content: &str) -> Vec<(String, String)> {
+    let mut outputs = Vec::new();
+    let mut lines = content.lines();
+
+    while let Some(line) = lines.next() {
+        if line.trim().is_empty() {
+            continue;
+        }
 
+        if let Some((key, delimiter)) = line.split_once("<<")
+            && !key.contains('=')
+        {
+            if delimiter.is_empty() {
+                continue;
+            }
 
-/// replace placeholders in the template with values
+            let mut value: Vec<&str> = Vec::new();
+            let mut is_closed = false;
+            for value_line in lines.by_ref() {
+                if value_line == delimiter {
+                    is_closed = true;
+                    break;
+                }
+                value.push(value_line);
+            }
+            if is_closed {
+                outputs.push((key.trim().to_string(), value.join("\n")));
+            }
+            continue;
+        }
 
-pub fn replace_placeholders(template: &str, values: &HashMap<String, String>) -> String {
-    let re = Regex::new(r"\{([^}]+)\}").unwrap();
+        if let Some((key, value)) = line.split_once('=') {
+            outputs.push((key.trim().to_string(), value.to_string()));
+        }
+    }
 
-    re.replace_all(template, |caps: &regex::Captures| {
-        let key = &caps[1];
-        values.get(key)
-            .map(|s| s.to_string()) // convert &str to String
-            .unwrap_or_else(|| caps[0].to_string()) // fallback: whole match
-    }).into_owned()
+    outputs
 }
 
 /// save the logs to the log path
@@ -381,7 +449,7 @@ pub async  fn save_log(log_path:&String,logs:String,build_id:String){
     let full_path = log_path;
 
     // Create logs directory if it doesn't exist
-    fs::create_dir_all( &full_path).expect("Failed to create logs directory");
+    fs::create_dir_all( full_path).expect("Failed to create logs directory");
 
     // Create a file inside ~/logs
     let mut file_path = PathBuf::from(&full_path);
@@ -425,13 +493,139 @@ pub async fn send_to_other_server(path:String,data:String) ->bool{
             let body = response.text().await.unwrap_or_default();
             println!("Successfully sent data to other server: {}", status);
             println!("Response body: {}", body);
-            return  true;
+            true
         }
         Err(err) => {
             println!("failed to send data to other server: {}", err);
-            return  false;
+            false
         } 
     }
 
 
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn output(pairs: &[(&str, &str)]) -> Vec<(String, String)> {
+        pairs.iter().map(|(key, value)| (key.to_string(), value.to_string())).collect()
+    }
+
+    #[test]
+    fn parses_plain_lines() {
+        let parsed = parse_builder_output("VERSION=1.2.3\n\nURL=https://x/?a=b\n");
+        assert_eq!(parsed, output(&[("VERSION", "1.2.3"), ("URL", "https://x/?a=b")]));
+    }
+
+    #[test]
+    fn parses_multi_line_values() {
+        let parsed = parse_builder_output("NOTES<<EOF\nfirst\n\nthird\nEOF\nNEXT=1\n");
+        assert_eq!(parsed, output(&[("NOTES", "first\n\nthird"), ("NEXT", "1")]));
+    }
+
+    #[test]
+    fn drops_an_unterminated_heredoc() {
+        let parsed = parse_builder_output("DONE=1\nNOTES<<EOF\nfirst\nsecond\n");
+        assert_eq!(parsed, output(&[("DONE", "1")]));
+    }
+
+    #[test]
+    fn keeps_a_heredoc_marker_after_the_equal_sign_in_the_value() {
+        let parsed = parse_builder_output("KEY=a<<b\n");
+        assert_eq!(parsed, output(&[("KEY", "a<<b")]));
+    }
+
+    #[test]
+    fn ignores_an_empty_delimiter() {
+        let parsed = parse_builder_output("KEY<<\nNEXT=1\n");
+        assert_eq!(parsed, output(&[("NEXT", "1")]));
+    }
+
+    /// a fresh project directory next to an outside one, removed on drop
+    struct Sandbox {
+        root: PathBuf,
+    }
+
+    impl Sandbox {
+        fn new() -> Self {
+            let root = std::env::temp_dir().join(format!("app_builder-test-{}", generate_token(16)));
+            fs::create_dir_all(root.join("base")).unwrap();
+            fs::create_dir_all(root.join("outside")).unwrap();
+            Self { root }
+        }
+
+        fn base(&self) -> String {
+            self.root.join("base").to_str().unwrap().to_string()
+        }
+
+        fn link(&self, target: &str, name: &str) {
+            std::os::unix::fs::symlink(self.root.join(target), self.root.join("base").join(name)).unwrap();
+        }
+
+        fn join(&self, user_input: &str) -> Option<String> {
+            secure_join_path(&self.base(), user_input)
+        }
+    }
+
+    impl Drop for Sandbox {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.root);
+        }
+    }
+
+    #[test]
+    fn accepts_a_new_nested_path() {
+        let sandbox = Sandbox::new();
+        let expected = fs::canonicalize(sandbox.base()).unwrap().join("config/new/.env");
+        assert_eq!(sandbox.join("config/new/.env").as_deref(), expected.to_str());
+        assert_eq!(sandbox.join("./config/../config/new/.env").as_deref(), expected.to_str());
+    }
+
+    #[test]
+    fn rejects_parent_escapes() {
+        let sandbox = Sandbox::new();
+        assert_eq!(sandbox.join("../outside/.env"), None);
+        assert_eq!(sandbox.join("config/../../outside/.env"), None);
+    }
+
+    #[test]
+    fn rejects_absolute_paths() {
+        let sandbox = Sandbox::new();
+        assert_eq!(sandbox.join("/etc/passwd"), None);
+        assert_eq!(sandbox.join(&format!("{}/.env", sandbox.base())), None);
+    }
+
+    #[test]
+    fn rejects_a_symlinked_ancestor_outside_the_base() {
+        let sandbox = Sandbox::new();
+        sandbox.link("outside", "config");
+        assert_eq!(sandbox.join("config/.env"), None);
+        assert_eq!(sandbox.join("config/new/.env"), None);
+    }
+
+    #[test]
+    fn rejects_a_dangling_symlink() {
+        let sandbox = Sandbox::new();
+        sandbox.link("outside/missing", ".env");
+        assert_eq!(sandbox.join(".env"), None);
+    }
+
+    #[test]
+    fn resolves_a_parent_after_a_symlink_inside_the_base() {
+        let sandbox = Sandbox::new();
+        fs::create_dir_all(sandbox.root.join("base/real/deep")).unwrap();
+        sandbox.link("base/real/deep", "link");
+        // lexically `link/..` is the base itself, not `real`
+        let expected = fs::canonicalize(sandbox.base()).unwrap().join("x");
+        assert_eq!(sandbox.join("link/../x").as_deref(), expected.to_str());
+    }
+
+    #[test]
+    fn rejects_the_base_itself() {
+        let sandbox = Sandbox::new();
+        assert_eq!(sandbox.join(""), None);
+        assert_eq!(sandbox.join("."), None);
+        assert_eq!(sandbox.join("config/.."), None);
+    }
+}
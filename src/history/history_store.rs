@@ -0,0 +1,97 @@
+use std::{io, path::{Path, PathBuf}};
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::models::{app_state::{AppState, BuildProcess}, status::Status};
+
+/// filters and page of a history listing, every filter is optional
+#[derive(Debug, Clone, Deserialize)]
+pub struct HistoryFilter {
+    pub status: Option<Status>,
+    pub unique_id: Option<String>,
+    /// builds started at or after
+    pub from: Option<DateTime<Utc>>,
+    /// builds started before
+    pub to: Option<DateTime<Utc>>,
+    /// fingerprint of the api token that queued the build, `/history` takes the token itself
+    pub triggered_by: Option<String>,
+    /// 1 based
+    #[serde(default="default_page")]
+    pub page: usize,
+    #[serde(default="default_per_page")]
+    pub per_page: usize,
+}
+
+impl HistoryFilter {
+    /// whether a build passes every set filter
+    pub fn matches(&self, build: &BuildProcess) -> bool {
+        self.status.as_ref().is_none_or(|status| &build.status == status)
+            && self.unique_id.as_ref().is_none_or(|unique_id| &build.unique_id == unique_id)
+            && self.from.is_none_or(|from| build.started_at >= from)
+            && self.to.is_none_or(|to| build.started_at < to)
+            && self.triggered_by.as_ref().is_none_or(|token| build.triggered_by.as_ref() == Some(token))
+    }
+
+    /// how many matches are skipped before the page, `per_page` is capped at 100
+    pub fn offset(&self) -> usize {
+        self.page.max(1).saturating_sub(1) * self.limit()
+    }
+
+    pub fn limit(&self) -> usize {
+        self.per_page.clamp(1, 100)
+    }
+}
+
+/// one page of finished builds, newest first
+#[derive(Debug, Clone, Serialize)]
+pub struct HistoryPage {
+    pub builds: Vec<BuildProcess>,
+    /// matches over all pages
+    pub total: usize,
+}
+
+/// where finished builds are kept
+/// the default is `JsonlHistoryStore`, embedders can pass their own to `AppState::with_history`
+pub trait HistoryStore: Send + Sync {
+    /// add a finished build
+    fn record(&self, build: &BuildProcess) -> io::Result<()>;
+
+    /// finished builds matching the filter, newest first
+    fn list(&self, filter: &HistoryFilter) -> io::Result<HistoryPage>;
+
+    /// a finished build by its id
+    fn get(&self, build_id: &str) -> io::Result<Option<BuildProcess>>;
+
+    /// keep a copy of the `file` payload `key` uploaded for a failed build, so a retry can write it again
+    /// runs on the blocking pool; stores that do not keep uploads leave this out,
+    /// their builds with uploads cannot be retried
+    fn keep_upload(&self, _build_id: &str, _key: &str, _path: &Path) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// the kept copy of an upload, none once it is dropped or expired
+    fn kept_upload(&self, _build_id: &str, _key: &str) -> io::Result<Option<PathBuf>> {
+        Ok(None)
+    }
+
+    /// remove the kept uploads of a build, called once it is retried or resumed
+    fn drop_uploads(&self, _build_id: &str) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// record a finished build, failures are only logged so the build itself is not affected
+pub fn record_build(state: &AppState, build: &BuildProcess) {
+    if let Err(e) = state.history.record(build) {
+        println!("Failed to record build {} in the history: {}", build.id, e);
+    }
+}
+
+fn default_page() -> usize {
+    1
+}
+
+fn default_per_page() -> usize {
+    20
+}
@@ -0,0 +1,143 @@
+use std::{fs, io::{self, BufRead, BufReader, Write}, os::unix::fs::{DirBuilderExt, OpenOptionsExt}, path::{Path, PathBuf}, sync::Mutex, time::Duration};
+
+use super::history_store::{HistoryFilter, HistoryPage, HistoryStore};
+use crate::{helpers::template::url_encode, models::app_state::BuildProcess};
+
+/// days an upload is kept for a retry, older copies are removed whenever another one is kept
+pub const KEPT_UPLOAD_DAYS: u64 = 7;
+
+/// history kept as one json line per finished build
+/// payloads can hold secrets, so the file is only readable by the owner
+/// uploads of failed builds are kept next to it, `history.uploads/<build id>/`, readable by the owner as well,
+/// until the build is retried or for `KEPT_UPLOAD_DAYS`
+/// the file is never trimmed and every `list` or `get` reads all of it, which stays fast for some
+/// thousand builds; longer histories should rotate the file or pass their own store
+pub struct JsonlHistoryStore {
+    path: PathBuf,
+    /// serializes the appends
+    lock: Mutex<()>,
+}
+
+impl JsonlHistoryStore {
+    pub fn new(path: PathBuf) -> Self {
+        Self { path, lock: Mutex::new(()) }
+    }
+
+    /// every readable build in the file, oldest first, broken lines are skipped
+    /// a full scan, there is no index
+    fn read_all(&self) -> io::Result<Vec<BuildProcess>> {
+        let file = match fs::File::open(&self.path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e),
+        };
+
+        let mut builds = Vec::new();
+        for line in BufReader::new(file).lines() {
+            let line = line?;
+            if let Ok(build) = serde_json::from_str::<BuildProcess>(&line) {
+                builds.push(build);
+            }
+        }
+        Ok(builds)
+    }
+
+    /// the directory the uploads of a build are kept in
+    fn upload_dir(&self, build_id: &str) -> PathBuf {
+        self.path.with_extension("uploads").join(build_id)
+    }
+
+    /// where the upload `key` of a build is kept, the key is encoded so it stays one file name
+    fn upload_path(&self, build_id: &str, key: &str) -> PathBuf {
+        self.upload_dir(build_id).join(format!("{}.upload", url_encode(key)))
+    }
+
+    /// remove the uploads kept longer than `KEPT_UPLOAD_DAYS`
+    fn prune_uploads(&self) {
+        let Ok(entries) = fs::read_dir(self.path.with_extension("uploads")) else {
+            return;
+        };
+
+        for entry in entries.flatten() {
+            if entry.metadata().is_ok_and(|metadata| is_expired(&metadata)) {
+                let _ = fs::remove_dir_all(entry.path());
+            }
+        }
+    }
+}
+
+/// whether a kept upload is older than `KEPT_UPLOAD_DAYS`
+fn is_expired(metadata: &fs::Metadata) -> bool {
+    let max_age = Duration::from_secs(KEPT_UPLOAD_DAYS * 24 * 60 * 60);
+    metadata.modified().ok()
+        .and_then(|modified| modified.elapsed().ok())
+        .is_some_and(|age| age > max_age)
+}
+
+impl HistoryStore for JsonlHistoryStore {
+    fn record(&self, build: &BuildProcess) -> io::Result<()> {
+        let _guard = self.lock.lock().unwrap_or_else(|e| e.into_inner());
+
+        if let Some(parent) = self.path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+
+        let mut line = serde_json::to_vec(build).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        line.push(b'\n');
+
+        let mut file = fs::OpenOptions::new()
+            .append(true)
+            .create(true)
+            .mode(0o600)
+            .open(&self.path)?;
+        file.write_all(&line)
+    }
+
+    fn list(&self, filter: &HistoryFilter) -> io::Result<HistoryPage> {
+        let matches: Vec<BuildProcess> = self.read_all()?
+            .into_iter()
+            .rev()
+            .filter(|build| filter.matches(build))
+            .collect();
+
+        let total = matches.len();
+        let builds = matches.into_iter().skip(filter.offset()).take(filter.limit()).collect();
+
+        Ok(HistoryPage { builds, total })
+    }
+
+    fn get(&self, build_id: &str) -> io::Result<Option<BuildProcess>> {
+        Ok(self.read_all()?.into_iter().rev().find(|build| build.id == build_id))
+    }
+
+    fn keep_upload(&self, build_id: &str, key: &str, path: &Path) -> io::Result<()> {
+        self.prune_uploads();
+
+        let destination = self.upload_path(build_id, key);
+        if let Some(parent) = destination.parent() {
+            fs::DirBuilder::new().recursive(true).mode(0o700).create(parent)?;
+        }
+
+        let mut file = fs::OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .mode(0o600)
+            .open(&destination)?;
+        io::copy(&mut fs::File::open(path)?, &mut file)?;
+        Ok(())
+    }
+
+    fn kept_upload(&self, build_id: &str, key: &str) -> io::Result<Option<PathBuf>> {
+        let path = self.upload_path(build_id, key);
+        let is_kept = fs::metadata(&path).is_ok_and(|metadata| metadata.is_file() && !is_expired(&metadata));
+        Ok(is_kept.then_some(path))
+    }
+
+    fn drop_uploads(&self, build_id: &str) -> io::Result<()> {
+        match fs::remove_dir_all(self.upload_dir(build_id)) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+            _ => Ok(()),
+        }
+    }
+}
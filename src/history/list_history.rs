@@ -0,0 +1,45 @@
+use actix_web::{web, HttpRequest, HttpResponse, Responder};
+use serde_json::json;
+
+use super::history_store::HistoryFilter;
+use crate::{auth::check_auth::{is_authorized, token_fingerprint}, build::build_status::{unauthorized, BuildQuery}, helpers::redact::build_response, models::{app_state::{AppState, BuildProcess}, status::Status}};
+
+/// page through the finished builds, newest first
+/// filters: `status`, `unique_id`, `from`/`to` (rfc 3339, on the start time),
+/// `triggered_by` (the api token, only its fingerprint is compared)
+pub async fn list_history(
+    req: HttpRequest,
+    filter: web::Query<HistoryFilter>,
+    query: web::Query<BuildQuery>,
+    state: web::Data<AppState>,
+) -> impl Responder {
+
+    if !is_authorized(&req, state.clone()).await {
+        return unauthorized();
+    }
+
+    let mut filter = filter.into_inner();
+    filter.triggered_by = filter.triggered_by.as_deref().map(token_fingerprint);
+
+    let page = match state.history.list(&filter) {
+        Ok(page) => page,
+        Err(e) => {
+            println!("Failed to read the build history: {}", e);
+            return HttpResponse::InternalServerError().json(json!({
+                "status": Status::SomethingWentWrong,
+                "message": "Failed to read the build history"
+            }));
+        }
+    };
+
+    let config = state.config();
+    let builds: Vec<BuildProcess> = page.builds.into_iter().map(|build| build_response(&config, build, query.logs)).collect();
+
+    HttpResponse::Ok().json(json!({
+        "status": "success",
+        "builds": builds,
+        "total": page.total,
+        "page": filter.page.max(1),
+        "per_page": filter.limit()
+    }))
+}
@@ -0,0 +1,3 @@
+pub mod history_store;
+pub mod jsonl_history;
+pub mod list_history;
@@ -0,0 +1,165 @@
+use std::{fs, io::{self, Write}, os::unix::fs::OpenOptionsExt, path::{Path, PathBuf}};
+
+use actix_web::web;
+use serde::{Deserialize, Serialize};
+
+use crate::{build::{build_manager::start_build_manager, payload_files::finish_staged_uploads}, history::history_store::record_build, error_success::handle_error_success::handle_error_success, models::{app_state::{AppState, BuildLog, BuildProcess, BuildRequest}, status::Status}};
+
+/// builds that were waiting or running when the journal was written
+#[derive(Default, Serialize, Deserialize)]
+pub struct BuildJournal {
+    pub queued: Vec<BuildRequest>,
+    pub running: Vec<BuildRequest>,
+}
+
+/// resolve the journal path against the user home, absolute paths are kept as is
+fn journal_file(journal_path: &str) -> io::Result<PathBuf> {
+    let home_dir = dirs::home_dir().ok_or_else(|| {
+        io::Error::new(io::ErrorKind::NotFound, "Could not determine the user home directory")
+    })?;
+
+    Ok(home_dir.join(Path::new(journal_path)))
+}
+
+/// where the uploads of a queued build wait until it starts, `<journal>.uploads/<build id>/`
+pub fn staged_uploads_dir(journal_path: &str, build_id: &str) -> io::Result<PathBuf> {
+    Ok(journal_file(journal_path)?.with_extension("uploads").join(build_id))
+}
+
+/// remove the staged uploads of builds that are no longer queued
+fn prune_staged_uploads(journal_path: &str, queued: &[BuildRequest]) {
+    let Ok(dir) = staged_uploads_dir(journal_path, "") else {
+        return;
+    };
+    let Ok(entries) = fs::read_dir(&dir) else {
+        return;
+    };
+
+    for entry in entries.flatten() {
+        let is_queued = queued.iter().any(|build| entry.file_name() == build.id.as_str());
+        if !is_queued {
+            let _ = fs::remove_dir_all(entry.path());
+        }
+    }
+}
+
+/// read the journal, a missing file is an empty journal
+pub fn load_journal(journal_path: &str) -> io::Result<BuildJournal> {
+    let path = journal_file(journal_path)?;
+    if !path.exists() {
+        return Ok(BuildJournal::default());
+    }
+
+    let content = fs::read_to_string(path)?;
+    serde_json::from_str(&content).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+/// write the journal through a temp file so a crash never leaves half a file behind
+/// payloads can hold secrets, so the file is only readable by the owner
+fn write_journal(journal_path: &str, journal: &BuildJournal) -> io::Result<()> {
+    let path = journal_file(journal_path)?;
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+
+    let tmp_path = path.with_extension("tmp");
+    let content = serde_json::to_vec(journal).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+
+    let mut file = fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(&tmp_path)?;
+    file.write_all(&content)?;
+    file.sync_all()?;
+
+    fs::rename(tmp_path, path)
+}
+
+/// persist the current queue and running builds
+/// call after every change of `build_queue` or `current_builds`
+pub async fn save_journal(state: &AppState) {
+    let _journal_guard = state.builds.journal_lock.lock().await;
+
+    let queued = state.builds.build_queue.lock().await.clone();
+    let running = state.builds.current_builds.lock().await
+        .values()
+        .map(|build| BuildRequest{
+            id: build.id.clone(),
+            unique_id: build.unique_id.clone(),
+            payload: build.payload.clone(),
+            socket_token: build.socket_token.clone(),
+            queued_at: build.queued_at,
+            triggered_by: build.triggered_by.clone(),
+            priority: build.priority,
+            skipped: 0,
+            pinned: false,
+            reuse_workspace: false,
+            uploaded_files: build.uploaded_files.clone(),
+            retry_of: build.retry_of.clone(),
+            start_step: build.start_step,
+            step_outputs: build.step_outputs.clone(),
+        })
+        .collect();
+
+    let journal = BuildJournal{ queued, running };
+
+    if let Err(e) = write_journal(&state.config().journal_path, &journal) {
+        println!("Failed to write build journal: {}", e);
+    }
+}
+
+/// restore the journal on startup
+/// queued builds go back into the queue with their staged uploads, builds that were running are reported as failed
+pub async fn restore_journal(state: &AppState) {
+    let journal = match load_journal(&state.config().journal_path) {
+        Ok(journal) => journal,
+        Err(e) => {
+            println!("Failed to read build journal, starting with an empty queue: {}", e);
+            return;
+        }
+    };
+
+    for build in &journal.running {
+        finish_staged_uploads(state, &build.id, &build.uploaded_files, true).await;
+    }
+    prune_staged_uploads(&state.config().journal_path, &journal.queued);
+
+    if journal.queued.is_empty() && journal.running.is_empty() {
+        return;
+    }
+
+    let data = web::Data::new(state.clone());
+    let config = state.config();
+
+    for build in journal.running {
+        println!("Build {} was interrupted by a restart", build.unique_id);
+
+        let mut build_process = BuildProcess::new(&build, config.project.build.commands.len());
+        build_process.status = Status::Error;
+        build_process.logs.push(BuildLog{
+            timestamp: chrono::Utc::now(),
+            status: Status::Error,
+            step: 0,
+            attempt: 1,
+            message: "Build interrupted by a restart of the builder".to_string(),
+        });
+
+        let data = data.clone();
+        let config = config.clone();
+        tokio::spawn(async move {
+            let finished_build = handle_error_success(data.clone(), &config, build_process).await;
+            record_build(&data, &finished_build);
+        });
+    }
+
+    let queued_count = journal.queued.len();
+    state.builds.build_queue.lock().await.extend(journal.queued);
+    save_journal(state).await;
+
+    if queued_count > 0 {
+        println!("Restored {} queued builds", queued_count);
+        start_build_manager(data).await;
+    }
+}
@@ -0,0 +1 @@
+pub mod build_journal;
\ No newline at end of file
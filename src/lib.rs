@@ -5,18 +5,81 @@ pub mod build;
 pub mod helpers;
 pub mod error_success;
 pub mod pending_update;
+pub mod journal;
+pub mod ssl;
+pub mod history;
+pub mod queue;
+pub mod shutdown;
+pub mod config_reload;
 
-pub fn add(left: u64, right: u64) -> u64 {
-    left + right
-}
+use actix_web::web;
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+use crate::{
+    build::{abort::{abort, abort_all}, build_init::build_initialize, build_status::{find_builds, get_build}, retry_build::{resume_build, retry_build}},
+    history::list_history::list_history,
+    models::app_state::AppState,
+    queue::manage_queue::{drop_build, list_queue, move_to_back, move_to_front, pause_queue, resume_queue},
+    pending_update::get_pending_update::get_pending_update,
+    socket::{
+        handle_socket::connect_and_stream_ws_build,
+        handle_socket_project::connect_and_stream_ws_project,
+        valid_project_token::set_valid_project_token,
+    },
+};
 
-    #[test]
-    fn it_works() {
-        let result = add(2, 2);
-        assert_eq!(result, 4);
-    }
+/// Registers every builder route under `prefix` (use `""` to mount at the root).
+///
+/// Route layout, relative to `prefix`:
+///
+/// | Method | Path                  | Handler                         |
+/// |--------|-----------------------|---------------------------------|
+/// | POST   | `/builds`             | `build_initialize`              |
+/// | GET    | `/builds`             | `find_builds` (`?unique_id=`)   |
+/// | POST   | `/builds/abort`       | `abort`                         |
+/// | POST   | `/builds/abort_all`   | `abort_all`                     |
+/// | GET    | `/builds/socket`      | `connect_and_stream_ws_build`   |
+/// | GET    | `/builds/{id}`        | `get_build` (`?logs=true`)      |
+/// | POST   | `/builds/{id}/retry`  | `retry_build`                   |
+/// | POST   | `/builds/{id}/resume` | `resume_build`                  |
+/// | GET    | `/project/socket`     | `connect_and_stream_ws_project` |
+/// | POST   | `/project/token`      | `set_valid_project_token`       |
+/// | GET    | `/pending_update`     | `get_pending_update`            |
+/// | GET    | `/history`            | `list_history`                  |
+/// | GET    | `/queue`              | `list_queue`                    |
+/// | POST   | `/queue/pause`        | `pause_queue`                   |
+/// | POST   | `/queue/resume`       | `resume_queue`                  |
+/// | POST   | `/queue/{id}/front`   | `move_to_front`                 |
+/// | POST   | `/queue/{id}/back`    | `move_to_back`                  |
+/// | DELETE | `/queue/{id}`         | `drop_build`                    |
+///
+/// The state is attached to the scope, so an embedding app does not need to
+/// register it itself:
+///
+/// ```ignore
+/// App::new().configure(|cfg| configure_routes(cfg, "/builder", state.clone()))
+/// ```
+pub fn configure_routes(cfg: &mut web::ServiceConfig, prefix: &str, state: web::Data<AppState>) {
+    cfg.service(
+        web::scope(prefix)
+            .app_data(state)
+            .route("/builds", web::post().to(build_initialize))
+            .route("/builds", web::get().to(find_builds))
+            .route("/builds/abort", web::post().to(abort))
+            .route("/builds/abort_all", web::post().to(abort_all))
+            .route("/builds/socket", web::get().to(connect_and_stream_ws_build))
+            // after the static paths, `{id}` would match them as well
+            .route("/builds/{id}", web::get().to(get_build))
+            .route("/builds/{id}/retry", web::post().to(retry_build))
+            .route("/builds/{id}/resume", web::post().to(resume_build))
+            .route("/project/socket", web::get().to(connect_and_stream_ws_project))
+            .route("/project/token", web::post().to(set_valid_project_token))
+            .route("/pending_update", web::get().to(get_pending_update))
+            .route("/history", web::get().to(list_history))
+            .route("/queue", web::get().to(list_queue))
+            .route("/queue/pause", web::post().to(pause_queue))
+            .route("/queue/resume", web::post().to(resume_queue))
+            .route("/queue/{id}/front", web::post().to(move_to_front))
+            .route("/queue/{id}/back", web::post().to(move_to_back))
+            .route("/queue/{id}", web::delete().to(drop_build)),
+    );
 }
@@ -0,0 +1,96 @@
+use std::process::exit;
+
+use actix_web::{web, App, HttpServer};
+
+use app_builder::{
+    config_reload::watch_config::watch_config,
+    configure_routes,
+    models::{app_state::AppState, config::Config},
+    shutdown::graceful_shutdown::{graceful_shutdown, shutdown_signal},
+    ssl::ssl_acceptor::{build_ssl_acceptor, watch_certificate},
+};
+
+const USAGE: &str = "Usage: app_builder serve --config <path>";
+
+/// read the config path out of `serve --config <path>` (or `--config=<path>`)
+fn parse_config_path(args: &[String]) -> Option<String> {
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        if arg == "--config" || arg == "-c" {
+            return args.next().cloned();
+        }
+        if let Some(path) = arg.strip_prefix("--config=") {
+            return Some(path.to_string());
+        }
+    }
+    None
+}
+
+/// starts the builder server, see `configure_routes` for the route layout
+#[actix_web::main]
+async fn main() -> std::io::Result<()> {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+
+    if args.first().map(String::as_str) != Some("serve") {
+        println!("{}", USAGE);
+        exit(1);
+    }
+
+    let Some(config_path) = parse_config_path(&args[1..]) else {
+        println!("Missing --config <path>\n{}", USAGE);
+        exit(1);
+    };
+
+    let config = match Config::load(&config_path) {
+        Ok(config) => config,
+        Err(e) => {
+            println!("Failed to load config {}: {}", config_path, e);
+            exit(1);
+        }
+    };
+
+    let port = config.port;
+    let ssl_config = config.ssl.clone();
+    println!("Starting {} on port {}", config.name, port);
+
+    let state = web::Data::new(AppState::new(config).await);
+    let shutdown_state = state.clone();
+
+    // commands, tokens and limits are picked up by the builds that start after a reload
+    tokio::spawn(watch_config(state.clone(), config_path));
+
+    let server = HttpServer::new(move || {
+        App::new()
+            .configure(|cfg| configure_routes(cfg, "", state.clone()))
+    });
+
+    let server = if ssl_config.enable_ssl {
+        let (builder, context) = match build_ssl_acceptor(&ssl_config) {
+            Ok(acceptor) => acceptor,
+            Err(e) => {
+                println!("Failed to setup ssl: {:#}", e);
+                exit(1);
+            }
+        };
+
+        tokio::spawn(watch_certificate(ssl_config, context));
+        server.bind_openssl(("0.0.0.0", port), builder)?
+    } else {
+        server.bind(("0.0.0.0", port))?
+    };
+
+    // signals are handled here, so the builds are drained before the workers stop
+    let server = server.disable_signals().run();
+    let server_handle = server.handle();
+
+    tokio::spawn(async move {
+        if let Err(e) = shutdown_signal().await {
+            println!("Failed to listen for shutdown signals: {}", e);
+            return;
+        }
+        graceful_shutdown(&shutdown_state).await;
+        server_handle.stop(true).await;
+    });
+
+    server.await
+}
@@ -1,23 +1,30 @@
+use crate::helpers::payload_schema::PayloadError;
+use crate::history::{history_store::HistoryStore, jsonl_history::JsonlHistoryStore};
 use crate::helpers::utils::{is_path_exits, read_token_from_user_home};
+use crate::journal::build_journal::restore_journal;
 
-use super::{config::Config, status::Status};
+use super::{cancel_token::CancelToken, config::{BuildPriority, Config, DuplicatePolicy}, status::Status};
 use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
-use std::{collections::HashMap, process::exit};
-use std::sync::Arc;
+use std::{collections::{BTreeMap, HashMap}, process::exit};
+use std::sync::{Arc, RwLock};
 use tokio::sync::{
-    Mutex,
+    Mutex, Notify,
     broadcast::{self},
 };
 
+/// the config shared between the handlers and the reload watcher, see `AppState::config`
+pub type SharedConfig = Arc<RwLock<Arc<Config>>>;
+
 #[derive(Clone)]
 pub struct AppState {
-    pub config: Config,
+    pub shared_config: SharedConfig,
     pub builds: BuildState,
+    pub history: Arc<dyn HistoryStore>,
     pub project_sender: broadcast::Sender<ChannelMessage>,
-    pub build_sender: broadcast::Sender<ChannelMessage>,
     pub is_queue_running: Arc<Mutex<bool>>,
-    pub is_terminated: Arc<Mutex<bool>>,
+    /// set on SIGTERM, no build is queued or started from then on
+    pub is_shutting_down: Arc<Mutex<bool>>,
     pub project_token: Arc< Mutex< Option<String> > >,
     pub project_logs:  Arc< Mutex< Vec<ProjectLog> > >,
 }
@@ -36,30 +43,97 @@ pub struct ProjectLog {
 #[derive(Clone)]
 pub struct BuildState {
     pub build_queue: Arc<Mutex<Vec<BuildRequest>>>,
-    pub current_build: Arc<Mutex<Option<BuildProcess>>>,
+    /// running builds keyed by build id
+    pub current_builds: Arc<Mutex<HashMap<String, BuildProcess>>>,
+    /// socket and termination handles of the running builds, keyed by build id
+    pub build_handles: Arc<Mutex<HashMap<String, BuildHandle>>>,
     pub failed_history: Arc<Mutex<Vec<BuildProcess>>>,
+    /// wakes the build manager when a build is queued
+    pub queue_notify: Arc<Notify>,
+    /// serializes writes of the on-disk journal
+    pub journal_lock: Arc<Mutex<()>>,
+    /// queued builds are not started while set
+    pub paused: Arc<Mutex<bool>>,
+}
+
+/// per build channels, lives as long as the build is running
+#[derive(Clone)]
+pub struct BuildHandle {
+    pub sender: broadcast::Sender<ChannelMessage>,
+    pub cancel: CancelToken,
+    /// the config the build started with, a reload does not change it
+    pub config: Arc<Config>,
+}
+
+impl BuildHandle {
+    pub fn new(config: Arc<Config>) -> Self {
+        let (sender, _) = broadcast::channel::<ChannelMessage>(100);
+        Self {
+            sender,
+            cancel: CancelToken::new(),
+            config,
+        }
+    }
+}
+
+impl Default for BuildState {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl  BuildState {
     pub fn new() -> Self {
         Self {
             build_queue: Arc::new(Mutex::new(Vec::new())),
-            current_build: Arc::new(Mutex::new(None)),
+            current_builds: Arc::new(Mutex::new(HashMap::new())),
+            build_handles: Arc::new(Mutex::new(HashMap::new())),
             failed_history: Arc::new(Mutex::new(Vec::new())),
+            queue_notify: Arc::new(Notify::new()),
+            journal_lock: Arc::new(Mutex::new(())),
+            paused: Arc::new(Mutex::new(false)),
         }
     }
 }
 
-#[derive(Clone)]
+#[derive(Clone, Serialize, Deserialize)]
 pub struct BuildRequest {
     pub id: String,
     pub unique_id: String,
     pub payload: HashMap<String, String>,
     pub socket_token: String,
+    #[serde(default="Utc::now")]
+    pub queued_at: DateTime<Utc>,
+    /// fingerprint of the api token the build was requested with
+    #[serde(default)]
+    pub triggered_by: Option<String>,
+    #[serde(default)]
+    pub priority: BuildPriority,
+    /// how many builds of a higher priority were started before this one
+    #[serde(default)]
+    pub skipped: u32,
+    /// moved to the front by an operator, starts before every other build
+    #[serde(default)]
+    pub pinned: bool,
+    /// `file` payloads that were uploaded, their content is not kept in the payload
+    #[serde(default)]
+    pub uploaded_files: Vec<String>,
+    /// keep the `file` payloads already in the project instead of writing them when the build starts
+    #[serde(default)]
+    pub reuse_workspace: bool,
+    /// the finished build this one retries
+    #[serde(default)]
+    pub retry_of: Option<String>,
+    /// first command to run, the ones before it are skipped
+    #[serde(default="first_step")]
+    pub start_step: usize,
+    /// values extracted by the skipped steps, restored when a build is resumed
+    #[serde(default)]
+    pub step_outputs: BTreeMap<usize, HashMap<String, String>>,
 }
 
 // #[derive()]
-#[derive(Clone,Serialize)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
 
 pub struct BuildProcess {
     pub id: String,
@@ -67,23 +141,101 @@ pub struct BuildProcess {
     pub status: Status,
     pub current_step: usize,
     pub total_steps: usize,
+    #[serde(default)]
+    pub queued_at: DateTime<Utc>,
     pub started_at: DateTime<Utc>,
     pub end_at: DateTime<Utc>,
     pub duration: i64,
     pub socket_token: String,
+    #[serde(default)]
+    pub triggered_by: Option<String>,
+    #[serde(default)]
+    pub priority: BuildPriority,
+    #[serde(default)]
+    pub uploaded_files: Vec<String>,
+    #[serde(default)]
+    pub retry_of: Option<String>,
+    #[serde(default="first_step")]
+    pub start_step: usize,
     pub payload: HashMap<String, String>,
     pub out_payload: HashMap<String, String>,
+    /// values the steps extracted through `$BUILDER_OUTPUT`
+    #[serde(default)]
+    pub outputs: HashMap<String, String>,
+    /// the same values by the step that extracted them
+    #[serde(default)]
+    pub step_outputs: BTreeMap<usize, HashMap<String, String>>,
+    /// attempts used per step, only steps that ran are listed
+    pub attempts: HashMap<usize, u32>,
     pub logs: Vec<BuildLog>,
 }
 
+impl BuildProcess {
+    /// a fresh build process for a dequeued request
+    pub fn new(build: &BuildRequest, total_steps: usize) -> Self {
+        // later steps overwrite the values of earlier ones
+        let outputs: HashMap<String, String> = build.step_outputs.values().flatten()
+            .map(|(key, value)| (key.clone(), value.clone()))
+            .collect();
+        let mut payload = build.payload.clone();
+        payload.extend(outputs.clone());
+
+        Self {
+            id: build.id.clone(),
+            unique_id: build.unique_id.clone(),
+            status: Status::Building,
+            current_step: build.start_step,
+            total_steps,
+            queued_at: build.queued_at,
+            started_at: Utc::now(),
+            end_at: Utc::now(),
+            duration: 0,
+            socket_token: build.socket_token.clone(),
+            triggered_by: build.triggered_by.clone(),
+            priority: build.priority,
+            uploaded_files: build.uploaded_files.clone(),
+            retry_of: build.retry_of.clone(),
+            start_step: build.start_step,
+            logs: Vec::new(),
+            payload,
+            out_payload: HashMap::new(),
+            outputs,
+            step_outputs: build.step_outputs.clone(),
+            attempts: HashMap::new(),
+        }
+    }
+
+    /// how a request that is still waiting in the queue is reported
+    pub fn queued(build: &BuildRequest, total_steps: usize) -> Self {
+        Self {
+            status: Status::Pending,
+            current_step: 0,
+            started_at: build.queued_at,
+            end_at: build.queued_at,
+            ..Self::new(build, total_steps)
+        }
+    }
+}
+
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct BuildLog {
     pub timestamp: DateTime<Utc>,
     pub status: Status,
     pub step: usize,
+    /// 1 for the first run of the step, counting up with every retry
+    #[serde(default="first_attempt")]
+    pub attempt: u32,
     pub message: String,
 }
 
+fn first_attempt() -> u32 {
+    1
+}
+
+fn first_step() -> usize {
+    1
+}
+
 #[derive(Serialize)]
 pub struct BuildResponse {
     pub message: String,
@@ -93,21 +245,68 @@ pub struct BuildResponse {
     // pub payload: Option<serde_json::Value>,
 }
 
+/// response of a build request that met a queued or running build of the same unique key
+#[derive(Serialize)]
+pub struct DuplicateResponse {
+    #[serde(flatten)]
+    pub response: BuildResponse,
+    pub policy: DuplicatePolicy,
+    /// the build that was refused against, replaced or cancelled
+    pub previous_build_id: String,
+}
+
+/// rejected payload, every invalid field is listed
+#[derive(Serialize)]
+pub struct ValidationResponse {
+    pub message: String,
+    pub status: Status,
+    pub errors: Vec<PayloadError>,
+}
+
 #[derive(Clone)]
 pub enum ChannelMessage {
     Data(String),
-    Shutdown,
+    /// the socket is closed with the reason
+    Shutdown(String),
 }
 
 impl AppState {
+    /// state with the default jsonl history at `config.history_path`
     pub async fn new(config: Config) -> Self {
+        let history_path = match dirs::home_dir() {
+            Some(home_dir) => home_dir.join(&config.history_path),
+            None => {
+                println!("Could not determine the user home directory for the build history");
+                exit(500);
+            }
+        };
+
+        Self::with_history(config, Arc::new(JsonlHistoryStore::new(history_path))).await
+    }
+
+    /// the current config, a snapshot that stays the same while it is held
+    pub fn config(&self) -> Arc<Config> {
+        match self.shared_config.read() {
+            Ok(config) => config.clone(),
+            Err(poisoned) => poisoned.into_inner().clone(),
+        }
+    }
+
+    /// replace the config for everything that starts from now on
+    pub fn set_config(&self, config: Config) {
+        let config = Arc::new(config);
+        match self.shared_config.write() {
+            Ok(mut current) => *current = config,
+            Err(poisoned) => *poisoned.into_inner() = config,
+        }
+    }
+
+    /// state keeping finished builds in a custom history store
+    pub async fn with_history(config: Config, history: Arc<dyn HistoryStore>) -> Self {
 
         let project_token = read_token_from_user_home(&config.token_path);
 
-        let project_token = match project_token {
-            Ok(token) => Some(token),
-            Err(_) => None,
-        };
+        let project_token = project_token.ok();
 
         let is_exist = is_path_exits(&config.project.project_path);
         if !is_exist {
@@ -117,18 +316,21 @@ impl AppState {
         
 
         let (project_sender, _) = broadcast::channel::<ChannelMessage>(100);
-        let (build_sender, _) = broadcast::channel::<ChannelMessage>(100);
 
-        Self {
-            config,
-            is_terminated: Arc::new(Mutex::new(false)),
+        let state = Self {
+            shared_config: Arc::new(RwLock::new(Arc::new(config))),
             project_sender,
-            build_sender,
             is_queue_running: Arc::new(Mutex::new(false)),
+            is_shutting_down: Arc::new(Mutex::new(false)),
             builds: BuildState::new(),
+            history,
             project_token: Arc::new(Mutex::new(project_token)),
             project_logs: Arc::new(Mutex::new(Vec::new())),
-        }
+        };
+
+        restore_journal(&state).await;
+
+        state
     }
 }
 
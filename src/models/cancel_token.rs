@@ -0,0 +1,63 @@
+use std::sync::Arc;
+
+use tokio::sync::watch;
+
+/// why a build was cancelled
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CancelReason {
+    /// through `abort`, `abort_all` or a duplicate policy
+    Aborted,
+    /// the build ran past `timeout_secs`
+    TimedOut,
+    /// the server is shutting down
+    Shutdown,
+}
+
+/// cancellation of a single build, every clone sees the same state
+/// the first reason wins, cancelling again is a no-op
+#[derive(Clone)]
+pub struct CancelToken {
+    sender: Arc<watch::Sender<Option<CancelReason>>>,
+}
+
+impl CancelToken {
+    pub fn new() -> Self {
+        let (sender, _) = watch::channel(None);
+        Self { sender: Arc::new(sender) }
+    }
+
+    pub fn cancel(&self, reason: CancelReason) {
+        self.sender.send_if_modified(|current| {
+            if current.is_some() {
+                return false;
+            }
+            *current = Some(reason);
+            true
+        });
+    }
+
+    pub fn reason(&self) -> Option<CancelReason> {
+        *self.sender.borrow()
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.reason().is_some()
+    }
+
+    /// resolves once the build is cancelled, right away when it already is
+    pub async fn cancelled(&self) -> CancelReason {
+        let mut receiver = self.sender.subscribe();
+        let reason = receiver.wait_for(Option::is_some).await.ok().and_then(|reason| *reason);
+        match reason {
+            Some(reason) => reason,
+            // the sender lives in `self`, so the channel can't close while waiting
+            None => std::future::pending().await,
+        }
+    }
+}
+
+impl Default for CancelToken {
+    fn default() -> Self {
+        Self::new()
+    }
+}
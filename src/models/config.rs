@@ -1,4 +1,6 @@
-use anyhow::Result;
+use std::collections::HashMap;
+
+use anyhow::{bail, Result};
 use serde::{Deserialize, Serialize};
 
 #[derive(Debug, Clone, Deserialize, Serialize)]
@@ -11,6 +13,15 @@ pub struct Config {
     pub auth: AuthConfig,
     pub project: ProjectConfig,
     pub token_path: String,
+    /// file keeping the queued and running builds across restarts, relative to the user home
+    #[serde(default="default_journal_path")]
+    pub journal_path: String,
+    /// jsonl file of the finished builds, relative to the user home
+    #[serde(default="default_history_path")]
+    pub history_path: String,
+    /// seconds between checks of the config file for changes, 0 reloads on SIGHUP only
+    #[serde(default="default_config_reload_interval")]
+    pub config_reload_interval: u64,
 }
 
 #[derive(Debug, Clone, Deserialize, Serialize)]
@@ -18,6 +29,9 @@ pub struct SslConfig {
     pub enable_ssl: bool,
     pub certificate_path: String,
     pub certificate_key_path: String,
+    /// seconds between checks of the certificate files for changes
+    #[serde(default="default_reload_interval")]
+    pub reload_interval: u64,
 }
 #[derive(Debug, Serialize, Clone, Deserialize)]
 #[serde(rename_all = "lowercase")]
@@ -40,25 +54,137 @@ pub struct AuthConfig {
     pub address_type: AddressType, // "ip", "hostname"
     pub allowed_addresses: Vec<String>,
     pub allowed_tokens: Vec<String>,
+    /// priority of the builds queued with a token unless the request sets one
+    #[serde(default)]
+    pub token_priorities: HashMap<String, BuildPriority>,
+}
+
+/// queued builds with a higher priority are started first
+#[derive(Debug, Clone, Copy, Default, Deserialize, Serialize, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "lowercase")]
+pub enum BuildPriority {
+    Low,
+    #[default]
+    Normal,
+    High,
+}
+
+impl std::str::FromStr for BuildPriority {
+    type Err = ();
+    fn from_str(input: &str) -> Result<BuildPriority, Self::Err> {
+        match input {
+            "low" => Ok(BuildPriority::Low),
+            "normal" => Ok(BuildPriority::Normal),
+            "high" => Ok(BuildPriority::High),
+            _ => Err(()),
+        }
+    }
 }
 
 #[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct ProjectConfig {
     pub allow_multi_build: bool,
+    /// number of builds running side by side when `allow_multi_build` is set
+    #[serde(default="default_max_concurrent_builds")]
+    pub max_concurrent_builds: usize,
     pub max_pending_build: u32,
+    /// a queued build is started next once this many builds of a higher priority went ahead of it,
+    /// 0 starts the builds in arrival order regardless of their priority
+    #[serde(default="default_max_priority_skips")]
+    pub max_priority_skips: u32,
+    /// what a build request does when a build of the same unique key is queued or running
+    #[serde(default)]
+    pub duplicate_policy: DuplicatePolicy,
     pub next_build_delay: u32,
+    /// seconds an aborted command gets between SIGTERM and SIGKILL
+    #[serde(default="default_abort_grace_period")]
+    pub abort_grace_period: u64,
     pub flush_interval: u32,
+    /// bytes a build request body may have, all fields and uploaded files together
+    #[serde(default="default_max_upload_size")]
+    pub max_upload_size: u64,
+    /// what happens to the running builds on SIGTERM
+    #[serde(default)]
+    pub shutdown_mode: ShutdownMode,
+    /// seconds the running builds get to drain, and again to finish their `run_on_failure` once aborted
+    #[serde(default="default_shutdown_timeout")]
+    pub shutdown_timeout_secs: u64,
     // pub base_endpoint_path: String,
     pub build: BuildConfig,
     pub project_path: String,
 
 }
 
+/// how a build request for a unique key that is already queued or running is handled
+#[derive(Debug, Clone, Copy, Default, Deserialize, Serialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum DuplicatePolicy {
+    /// the request is refused with `already_building` or `already_queue`
+    #[default]
+    Reject,
+    /// the queued build takes the new payload, without a queued one the build waits for the running one
+    Replace,
+    /// the running build is aborted, a queued one dropped, and the new build queued
+    CancelRunningAndRestart,
+}
+
+/// how the running builds are stopped when the server shuts down
+#[derive(Debug, Clone, Copy, Default, Deserialize, Serialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ShutdownMode {
+    /// wait for the running builds, they are aborted once `shutdown_timeout_secs` passes
+    #[default]
+    Drain,
+    /// abort the running builds right away, their `run_on_failure` still runs
+    Abort,
+}
+
+impl ProjectConfig {
+    /// how many builds may run at the same time
+    pub fn build_slots(&self) -> usize {
+        if self.allow_multi_build {
+            self.max_concurrent_builds.max(1)
+        } else {
+            1
+        }
+    }
+}
+
 #[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct Payload {
     pub r#type: PayloadType,
     pub key1: String,
     pub key2: Option<String>,
+    /// a missing required value fails the request, a missing optional one is left out
+    #[serde(default="default_required")]
+    pub required: bool,
+    /// used when the value is missing, it is validated like a sent value
+    #[serde(default)]
+    pub default: Option<String>,
+    #[serde(default)]
+    pub value_type: ValueType,
+    /// the allowed values of an `enum`
+    #[serde(default)]
+    pub values: Vec<String>,
+    /// regex the whole value has to match
+    #[serde(default)]
+    pub pattern: Option<String>,
+    /// hidden in the queue listing, `file` payloads always are
+    #[serde(default)]
+    pub secret: bool,
+}
+
+/// type a payload value is checked against
+#[derive(Debug, Clone, Default, Deserialize, Serialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum ValueType {
+    #[default]
+    String,
+    Int,
+    /// `true` or `false`, `1` and `0` are accepted as well
+    Bool,
+    /// one of `values`
+    Enum,
 }
 
 #[derive(Debug, Deserialize, Clone, Serialize,PartialEq)]
@@ -92,6 +218,9 @@ pub struct BuildConfig {
     pub run_on_success: Vec<CommandConfig>,
     #[serde(default)]
     pub run_on_failure: Vec<CommandConfig>,
+    /// limit for the whole build, the hooks are not counted
+    #[serde(default)]
+    pub timeout_secs: Option<u64>,
 }
 
 #[derive(Debug, Clone, Deserialize, Serialize)]
@@ -104,15 +233,103 @@ pub struct CommandConfig {
     pub abort_on_error: bool, // "abort", "continue"
     #[serde(default="default_to_sock")]
     pub send_to_sock: bool,
+    /// the command is killed when it runs longer than this
+    #[serde(default)]
+    pub timeout_secs: Option<u64>,
+    /// the command is killed after this many seconds without a line on stdout or stderr
+    #[serde(default)]
+    pub no_output_timeout: Option<u64>,
+    #[serde(default)]
+    pub no_output_action: NoOutputAction,
+    /// extra attempts after a failed one
+    #[serde(default)]
+    pub retries: u32,
+    #[serde(default="default_retry_delay")]
+    pub retry_delay_secs: u64,
+    /// only these exit codes are retried, any failure when unset
+    #[serde(default)]
+    pub retry_on_exit_codes: Option<Vec<i32>>,
+
 
 }
 
+/// what happens to the build when a command stops producing output
+#[derive(Debug, Clone, Default, Deserialize, Serialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum NoOutputAction {
+    /// the step fails, `abort_on_error` decides whether the build goes on
+    #[default]
+    Fail,
+    /// the whole build is aborted
+    Abort,
+}
+
 impl Config {
     pub fn load(path: &str) -> Result<Self> {
         let content = std::fs::read_to_string(path)?;
         let config: Config = toml::from_str(&content)?;
+        config.validate()?;
         Ok(config)
     }
+
+    /// check what the toml format can not express
+    pub fn validate(&self) -> Result<()> {
+        for payload in &self.project.build.payload {
+            if let Some(pattern) = &payload.pattern
+                && let Err(e) = regex::Regex::new(pattern)
+            {
+                bail!("Invalid pattern of payload {}: {}", payload.key1, e);
+            }
+            if payload.value_type == ValueType::Enum && payload.values.is_empty() {
+                bail!("Payload {} is an enum without values", payload.key1);
+            }
+        }
+        Ok(())
+    }
+}
+
+fn default_abort_grace_period() -> u64 {
+    10
+}
+
+fn default_journal_path() -> String {
+    ".app_builder/journal.json".to_string()
+}
+
+fn default_max_priority_skips() -> u32 {
+    3
+}
+
+fn default_history_path() -> String {
+    ".app_builder/history.jsonl".to_string()
+}
+
+fn default_max_upload_size() -> u64 {
+    50 * 1024 * 1024
+}
+
+fn default_shutdown_timeout() -> u64 {
+    60
+}
+
+fn default_max_concurrent_builds() -> usize {
+    2
+}
+
+fn default_required() -> bool {
+    true
+}
+
+fn default_config_reload_interval() -> u64 {
+    5
+}
+
+fn default_reload_interval() -> u64 {
+    60
+}
+
+fn default_retry_delay() -> u64 {
+    5
 }
 
 fn default_to_sock() -> bool {
@@ -1,3 +1,4 @@
 pub mod app_state;
+pub mod cancel_token;
 pub mod config;
 pub mod status;
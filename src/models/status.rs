@@ -10,6 +10,7 @@ pub enum Status {
     Full,
     AlreadyBuilding,
     AlreadyQueue,
+    Replaced,
     Aborted,
     NotFound,
     SomethingWentWrong,
@@ -17,19 +18,27 @@ pub enum Status {
     MissingUniqueId,
     MaxPending,
     MissingPayload,
+    InvalidPayload,
     FileCreateFailed,
     MissingProjectToken,
     StartingCommand,
     ChangeProjectToken,
+    TimedOut,
+    Warning,
+    ShuttingDown,
 }
 
 impl Status {
     pub fn as_str(&self) -> &'static str {
         match self {
             Status::ChangeProjectToken => "change_project_token",
+            Status::TimedOut => "timed_out",
+            Status::Warning => "warning",
+            Status::ShuttingDown => "shutting_down",
             Status::StartingCommand => "starting_command",
             Status::FileCreateFailed => "file_create_failed",
             Status::MissingPayload => "missing_payload",
+            Status::InvalidPayload => "invalid_payload",
             Status::Error => "error",
             Status::Success => "success",
             Status::Pending => "pending",
@@ -37,6 +46,7 @@ impl Status {
             Status::Full => "full",
             Status::AlreadyBuilding => "already_building",
             Status::AlreadyQueue => "already_queue",
+            Status::Replaced => "replaced",
             Status::Aborted => "aborted",
             Status::NotFound => "not_found",
             Status::SomethingWentWrong => "something_went_wrong",
@@ -2,11 +2,12 @@
 use actix_web::{web, HttpRequest, HttpResponse, Responder};
 use serde_json::json;
 
-use crate::{auth::check_auth::is_authorized, models::{app_state::{AppState, BuildResponse}, status::Status}};
+use crate::{auth::check_auth::is_authorized, helpers::redact::build_response, models::{app_state::{AppState, BuildProcess, BuildResponse}, status::Status}};
 
 
 
 /// get all pending failed attempt while sending to other server
+/// the builds are redacted like every other build response, the logs are kept
 pub async fn get_pending_update(
     req: HttpRequest,
     state: web::Data<AppState>,
@@ -24,7 +25,10 @@ pub async fn get_pending_update(
     }
 
     let  error_history_guard = &mut state.builds.failed_history.lock().await;
-    let error_history = error_history_guard.to_vec();
+    let config = state.config();
+    let error_history: Vec<BuildProcess> = error_history_guard.iter()
+        .map(|build| build_response(&config, build.clone(), true))
+        .collect();
 
 
     let queue_count: usize;
@@ -41,5 +45,5 @@ pub async fn get_pending_update(
 
     error_history_guard.clear();
 
-    return HttpResponse::Ok().json(json_str);
+    HttpResponse::Ok().json(json_str)
 }
\ No newline at end of file
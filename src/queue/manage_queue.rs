@@ -0,0 +1,175 @@
+use std::collections::{HashMap, HashSet};
+
+use actix_web::{web, HttpRequest, HttpResponse, Responder};
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use serde_json::json;
+
+use crate::{auth::check_auth::is_authorized, build::{build_manager::{start_build_manager, start_order}, build_status::unauthorized, payload_files::discard_staged_uploads}, helpers::redact::redact_payload, journal::build_journal::save_journal, models::{app_state::{AppState, BuildRequest, BuildResponse, ChannelMessage}, config::{BuildPriority, Config}, status::Status}};
+
+/// a queued build as shown to operators
+#[derive(Serialize)]
+pub struct QueueEntry {
+    pub position: usize,
+    pub id: String,
+    pub unique_id: String,
+    pub queued_at: DateTime<Utc>,
+    pub triggered_by: Option<String>,
+    pub priority: BuildPriority,
+    pub skipped: u32,
+    /// only the unique key and the `param`/`env` values not marked `secret`, the rest is redacted
+    pub payload: HashMap<String, String>,
+}
+
+/// the queued builds in the order they would start
+fn queue_entries(config: &Config, queue: &[BuildRequest], running: &HashSet<String>) -> Vec<QueueEntry> {
+    start_order(queue, running, config.project.max_priority_skips).into_iter().map(|index| &queue[index]).enumerate().map(|(index, build)| QueueEntry {
+        position: index + 1,
+        id: build.id.clone(),
+        unique_id: build.unique_id.clone(),
+        queued_at: build.queued_at,
+        triggered_by: build.triggered_by.clone(),
+        priority: build.priority,
+        skipped: build.skipped,
+        payload: redact_payload(config, &build.payload),
+    }).collect()
+}
+
+/// the queue listing, also broadcast on the project socket after every change
+async fn queue_snapshot(state: &AppState) -> serde_json::Value {
+    let running: HashSet<String> = state.builds.current_builds.lock().await
+        .values()
+        .map(|build| build.unique_id.clone())
+        .collect();
+    let queue = state.builds.build_queue.lock().await;
+    json!({
+        "paused": *state.builds.paused.lock().await,
+        "queue": queue_entries(&state.config(), &queue, &running),
+    })
+}
+
+/// tell the project socket subscribers what changed in the queue
+async fn broadcast_queue(state: &AppState, action: &str, build_id: Option<&str>) {
+    let mut event = queue_snapshot(state).await;
+    event["event"] = json!("queue");
+    event["action"] = json!(action);
+    event["build_id"] = json!(build_id);
+
+    let _ = state.project_sender.send(ChannelMessage::Data(event.to_string()));
+}
+
+fn not_found(build_id: String) -> HttpResponse {
+    let res = BuildResponse{
+        message: format!("No queued build {}", build_id),
+        status: Status::NotFound,
+        build_id: Some(build_id),
+        token: None
+    };
+    HttpResponse::NotFound().json(res)
+}
+
+/// list the queued builds
+pub async fn list_queue(req: HttpRequest, state: web::Data<AppState>) -> impl Responder {
+    if !is_authorized(&req, state.clone()).await {
+        return unauthorized();
+    }
+
+    let mut snapshot = queue_snapshot(&state).await;
+    snapshot["status"] = json!("success");
+    HttpResponse::Ok().json(snapshot)
+}
+
+/// move a queued build to the front or the back of the queue
+/// a build moved to the front is pinned and starts next whatever its priority,
+/// one moved to the back loses its pin and its skips and waits behind the builds of its priority
+async fn move_build(req: HttpRequest, path: web::Path<String>, state: web::Data<AppState>, to_front: bool) -> HttpResponse {
+    if !is_authorized(&req, state.clone()).await {
+        return unauthorized();
+    }
+
+    let build_id = path.into_inner();
+    {
+        let mut queue = state.builds.build_queue.lock().await;
+        let Some(index) = queue.iter().position(|build| build.id == build_id) else {
+            return not_found(build_id);
+        };
+
+        let mut build = queue.remove(index);
+        build.pinned = to_front;
+        if to_front {
+            queue.insert(0, build);
+        } else {
+            build.skipped = 0;
+            queue.push(build);
+        }
+    }
+    save_journal(&state).await;
+
+    let action = if to_front { "moved_to_front" } else { "moved_to_back" };
+    broadcast_queue(&state, action, Some(&build_id)).await;
+
+    HttpResponse::Ok().json(queue_snapshot(&state).await)
+}
+
+/// move a queued build to the front of the queue
+pub async fn move_to_front(req: HttpRequest, path: web::Path<String>, state: web::Data<AppState>) -> impl Responder {
+    move_build(req, path, state, true).await
+}
+
+/// move a queued build to the back of the queue
+pub async fn move_to_back(req: HttpRequest, path: web::Path<String>, state: web::Data<AppState>) -> impl Responder {
+    move_build(req, path, state, false).await
+}
+
+/// drop a queued build, it is not reported to the callback
+pub async fn drop_build(req: HttpRequest, path: web::Path<String>, state: web::Data<AppState>) -> impl Responder {
+    if !is_authorized(&req, state.clone()).await {
+        return unauthorized();
+    }
+
+    let build_id = path.into_inner();
+    {
+        let mut queue = state.builds.build_queue.lock().await;
+        let Some(index) = queue.iter().position(|build| build.id == build_id) else {
+            return not_found(build_id);
+        };
+        queue.remove(index);
+    }
+    save_journal(&state).await;
+    discard_staged_uploads(&state.config().journal_path, &build_id).await;
+
+    broadcast_queue(&state, "dropped", Some(&build_id)).await;
+
+    let res = BuildResponse{
+        message: "Removed from the queue".to_string(),
+        status: Status::Aborted,
+        build_id: Some(build_id),
+        token: None
+    };
+    HttpResponse::Ok().json(res)
+}
+
+/// stop starting queued builds, running ones go on
+pub async fn pause_queue(req: HttpRequest, state: web::Data<AppState>) -> impl Responder {
+    if !is_authorized(&req, state.clone()).await {
+        return unauthorized();
+    }
+
+    *state.builds.paused.lock().await = true;
+    broadcast_queue(&state, "paused", None).await;
+
+    HttpResponse::Ok().json(queue_snapshot(&state).await)
+}
+
+/// start queued builds again
+pub async fn resume_queue(req: HttpRequest, state: web::Data<AppState>) -> impl Responder {
+    if !is_authorized(&req, state.clone()).await {
+        return unauthorized();
+    }
+
+    *state.builds.paused.lock().await = false;
+    start_build_manager(state.clone()).await;
+    broadcast_queue(&state, "resumed", None).await;
+
+    HttpResponse::Ok().json(queue_snapshot(&state).await)
+}
@@ -0,0 +1,64 @@
+use std::time::Duration;
+
+use tokio::signal::unix::{signal, SignalKind};
+
+use crate::{helpers::utils::remove_private_dir, journal::build_journal::save_journal, models::{app_state::{AppState, BuildHandle, ChannelMessage}, cancel_token::CancelReason, config::ShutdownMode}};
+
+/// reason the sockets are closed with when the server goes down
+pub const SHUTDOWN_REASON: &str = "Server is shutting down";
+
+/// resolves on SIGTERM or SIGINT
+pub async fn shutdown_signal() -> std::io::Result<()> {
+    let mut terminate = signal(SignalKind::terminate())?;
+    tokio::select! {
+        _ = terminate.recv() => {}
+        result = tokio::signal::ctrl_c() => result?,
+    }
+    Ok(())
+}
+
+/// resolves once no build is running
+async fn wait_for_running_builds(state: &AppState) {
+    while !state.builds.current_builds.lock().await.is_empty() {
+        tokio::time::sleep(Duration::from_millis(200)).await;
+    }
+}
+
+/// stop accepting builds, drain or abort the running ones, persist the queue and close the sockets
+/// aborted builds go through `run_on_failure` and their callback like any other failed build
+pub async fn graceful_shutdown(state: &AppState) {
+    *state.is_shutting_down.lock().await = true;
+
+    let config = state.config();
+    let timeout = Duration::from_secs(config.project.shutdown_timeout_secs);
+
+    if config.project.shutdown_mode == ShutdownMode::Drain {
+        println!("Shutting down, waiting up to {}s for the running builds", timeout.as_secs());
+        let _ = tokio::time::timeout(timeout, wait_for_running_builds(state)).await;
+    }
+
+    let handles: Vec<BuildHandle> = state.builds.build_handles.lock().await.values().cloned().collect();
+    if !handles.is_empty() {
+        println!("Shutting down, aborting {} running builds", handles.len());
+        for handle in &handles {
+            handle.cancel.cancel(CancelReason::Shutdown);
+        }
+
+        if tokio::time::timeout(timeout, wait_for_running_builds(state)).await.is_err() {
+            println!("Builds still running after {}s, they are reported as failed on the next start", timeout.as_secs());
+        }
+    }
+
+    // the queue is picked up again on the next start
+    save_journal(state).await;
+
+    let message = ChannelMessage::Shutdown(SHUTDOWN_REASON.to_string());
+    let _ = state.project_sender.send(message.clone());
+    for handle in state.builds.build_handles.lock().await.values() {
+        let _ = handle.sender.send(message.clone());
+    }
+
+    remove_private_dir();
+
+    println!("Shutdown complete, {} builds left in the queue", state.builds.build_queue.lock().await.len());
+}
@@ -0,0 +1 @@
+pub mod graceful_shutdown;
\ No newline at end of file
@@ -1,7 +1,7 @@
 use std::{collections::HashMap};
 
 use actix_web::{ web, Error, HttpRequest, HttpResponse};
-use actix_ws::handle;
+use actix_ws::{handle, CloseCode, CloseReason};
 
 use crate::models::app_state::{AppState, ChannelMessage};
 
@@ -27,41 +27,32 @@ pub async fn connect_and_stream_ws_build(
     |--------------------------------------------------------------------------
     |
     */
-    let unique_build_key = &data.config.project.build.unique_build_key;
-
-    // let build_id = query.get(unique_build_key).clone(); 
-    let token = query.get("token").clone(); 
+    let token = query.get("token"); 
     println!("Connecting to build websocket");
-    println!("Token: {:?}",token);
-    if let None = token {
+    if token.is_none() {
         return Ok(HttpResponse::Unauthorized().body("Socket Token is Required"));
     }
-    // println!("Token: {:?}",token);
-
-    // if let None = build_id {
-    //     return Ok(HttpResponse::Unauthorized().body(format!("No build id found with key {} ",unique_build_key)));
-    // }
-    // let build_id = build_id.unwrap();
     let token = token.unwrap();
 
-    
-    println!("Connecting to build websocket 2");
-
 
-    let state = data.as_ref().clone();
-    // let current_token_lock = state.token.lock().await;
+    // the socket token identifies which of the running builds to stream
+    let current_builds = data.builds.current_builds.lock().await;
+    let current_build = current_builds.values().find(|build| &build.socket_token == token);
+    if current_build.is_none() {
+        return Ok(HttpResponse::Unauthorized().body("No running build found for the token"));
+    }
+    let current_build = current_build.unwrap();
 
-    let current_build_guard = state.builds.current_build.lock().await;
-    if current_build_guard.is_none() {
+    let build_handle = data.builds.build_handles.lock().await.get(&current_build.id).cloned();
+    if build_handle.is_none() {
         return Ok(HttpResponse::Unauthorized().body("No build is running"));
     }
-    let current_build  = current_build_guard.as_ref().unwrap();
-    // if &current_build.unique_id != build_id {
-    //     return Ok(HttpResponse::Unauthorized().body("Invalid build id"));
-    // }
-    // if &current_build.socket_token != token {
-    //     return Ok(HttpResponse::Unauthorized().body("Invalid token"));
-    // }
+
+    // Subscribe before reading the buffer so no line falls in between
+    let mut rx = build_handle.unwrap().sender.subscribe();
+    let buf = current_build.logs.clone();
+    drop(current_builds);
+
     println!("Connecting to build websocket success");
 
 
@@ -69,17 +60,10 @@ pub async fn connect_and_stream_ws_build(
 
     // Send old buffered messages first
     {
-        let buf = current_build.logs.clone();
-        drop(current_build_guard);
         let json_array = serde_json::to_string(&*buf).unwrap();
-        // for line in buf.iter() {
-            let _ = session.text(json_array).await;
-        // }
+        let _ = session.text(json_array).await;
     }
 
-    // Subscribe to broadcast channel
-    let mut rx = data.build_sender.subscribe();
-    
     // Stream new output to client
     actix_web::rt::spawn(async move {
         while let Ok(line) = rx.recv().await {
@@ -92,8 +76,9 @@ pub async fn connect_and_stream_ws_build(
                         break;
                     };
                 }
-                ChannelMessage::Shutdown => {
-                    session.close(None).await.unwrap_or_default();
+                ChannelMessage::Shutdown(reason) => {
+                    let reason = CloseReason { code: CloseCode::Normal, description: Some(reason) };
+                    session.close(Some(reason)).await.unwrap_or_default();
                     break;
                 }
                 
@@ -1,7 +1,7 @@
 use std::{collections::HashMap};
 
 use actix_web::{ web, Error, HttpRequest, HttpResponse};
-use actix_ws::handle;
+use actix_ws::{handle, CloseCode, CloseReason};
 
 use crate::models::app_state::{AppState, ChannelMessage};
 
@@ -20,7 +20,7 @@ pub async fn connect_and_stream_ws_project(
     // |--------------------------------------------------------------------------
     // |
     // */
-    let token = query.get("token").clone(); 
+    let token = query.get("token"); 
    
 
 
@@ -78,8 +78,9 @@ pub async fn connect_and_stream_ws_project(
                         break;
                     };
                 }
-                ChannelMessage::Shutdown => {
-                    session.close(None).await.unwrap_or_default();
+                ChannelMessage::Shutdown(reason) => {
+                    let reason = CloseReason { code: CloseCode::Normal, description: Some(reason) };
+                    session.close(Some(reason)).await.unwrap_or_default();
                     break;
                 }
                 
@@ -2,7 +2,7 @@ use std::collections::HashMap;
 
 use actix_web::{web, HttpRequest, HttpResponse, Responder};
 
-use crate::{auth::check_auth::is_authorized, helpers::utils::{create_file_with_dirs_and_content, save_token_to_user_home}, models::{app_state::{AppState, BuildResponse}, status::Status}};
+use crate::{auth::check_auth::is_authorized, helpers::utils::save_token_to_user_home, models::{app_state::{AppState, BuildResponse}, status::Status}};
 
 
 
@@ -37,7 +37,7 @@ pub async fn set_valid_project_token(req: HttpRequest,payload: web::Json<HashMap
     
     println!("project_token_s: {}", project_token_s);
 
-    let is_created = save_token_to_user_home(&state.config.token_path.as_str(), project_token_s);
+    let is_created = save_token_to_user_home(state.config().token_path.as_str(), project_token_s);
     if is_created.is_err() {
         let res = BuildResponse{
             message: "Failed to save project token".to_string(),
@@ -58,7 +58,7 @@ pub async fn set_valid_project_token(req: HttpRequest,payload: web::Json<HashMap
             build_id: None,
             token: None
         };
-        return HttpResponse::Ok().json(res);
+        HttpResponse::Ok().json(res)
    
 
 
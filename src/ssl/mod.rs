@@ -0,0 +1 @@
+pub mod ssl_acceptor;
\ No newline at end of file
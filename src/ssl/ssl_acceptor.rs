@@ -0,0 +1,99 @@
+use std::{fs, sync::{Arc, RwLock}, time::{Duration, SystemTime}};
+
+use anyhow::{Context, Result};
+use openssl::ssl::{AlpnError, SniError, SslAcceptor, SslAcceptorBuilder, SslContext, SslFiletype, SslMethod};
+
+use crate::models::config::SslConfig;
+
+/// certificate context shared between the acceptor and the reload watcher
+pub type SharedSslContext = Arc<RwLock<SslContext>>;
+
+/// create an acceptor builder loaded with the configured certificate and key
+fn acceptor_builder(ssl_config: &SslConfig) -> Result<SslAcceptorBuilder> {
+    let mut builder = SslAcceptor::mozilla_intermediate_v5(SslMethod::tls())?;
+
+    builder
+        .set_private_key_file(&ssl_config.certificate_key_path, SslFiletype::PEM)
+        .with_context(|| format!("Failed to load certificate key {}", ssl_config.certificate_key_path))?;
+    builder
+        .set_certificate_chain_file(&ssl_config.certificate_path)
+        .with_context(|| format!("Failed to load certificate {}", ssl_config.certificate_path))?;
+    builder.check_private_key().context("Certificate and key do not match")?;
+
+    Ok(builder)
+}
+
+/// load the certificate into a standalone context that can be swapped into new connections
+/// the alpn selection mirrors the one actix sets on the main acceptor
+pub fn load_ssl_context(ssl_config: &SslConfig) -> Result<SslContext> {
+    let mut builder = acceptor_builder(ssl_config)?;
+
+    builder.set_alpn_select_callback(|_, protocols| {
+        const H2: &[u8] = b"\x02h2";
+        const H11: &[u8] = b"\x08http/1.1";
+
+        if protocols.windows(3).any(|window| window == H2) {
+            Ok(b"h2")
+        } else if protocols.windows(9).any(|window| window == H11) {
+            Ok(b"http/1.1")
+        } else {
+            Err(AlpnError::NOACK)
+        }
+    });
+
+    Ok(builder.build().into_context())
+}
+
+/// build the acceptor for `bind_openssl`
+/// every handshake picks up the latest certificate from the returned shared context,
+/// so replacing it (see `watch_certificate`) does not need a restart
+pub fn build_ssl_acceptor(ssl_config: &SslConfig) -> Result<(SslAcceptorBuilder, SharedSslContext)> {
+    let context: SharedSslContext = Arc::new(RwLock::new(load_ssl_context(ssl_config)?));
+
+    let mut builder = acceptor_builder(ssl_config)?;
+    let callback_context = context.clone();
+
+    // the servername callback runs for every client hello, with or without sni
+    builder.set_servername_callback(move |ssl, _alert| {
+        let context = callback_context.read().map_err(|_| SniError::ALERT_FATAL)?;
+        ssl.set_ssl_context(&context).map_err(|_| SniError::ALERT_FATAL)
+    });
+
+    Ok((builder, context))
+}
+
+/// latest modification time of the certificate or the key
+fn certificate_modified(ssl_config: &SslConfig) -> Option<SystemTime> {
+    let cert = fs::metadata(&ssl_config.certificate_path).and_then(|m| m.modified()).ok()?;
+    let key = fs::metadata(&ssl_config.certificate_key_path).and_then(|m| m.modified()).ok()?;
+    Some(cert.max(key))
+}
+
+/// poll the certificate files and reload the shared context when they change
+/// a broken or half written certificate keeps the previous one in place
+pub async fn watch_certificate(ssl_config: SslConfig, context: SharedSslContext) {
+    let mut last_modified = certificate_modified(&ssl_config);
+    let mut interval = tokio::time::interval(Duration::from_secs(ssl_config.reload_interval.max(1)));
+
+    loop {
+        interval.tick().await;
+
+        let modified = certificate_modified(&ssl_config);
+        if modified.is_none() || modified == last_modified {
+            continue;
+        }
+
+        match load_ssl_context(&ssl_config) {
+            Ok(new_context) => {
+                if let Ok(mut guard) = context.write() {
+                    *guard = new_context;
+                }
+                last_modified = modified;
+                println!("Reloaded ssl certificate {}", ssl_config.certificate_path);
+            }
+            Err(e) => {
+                println!("Failed to reload ssl certificate, keeping the previous one: {:#}", e);
+            }
+        }
+    }
+}